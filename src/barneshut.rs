@@ -1,57 +1,306 @@
-use physics::{Particle, PhysVec, force, stepvel};
-use std::{fmt, f64};
-use std::sync::{Arc, mpsc};
-use std::thread;
-use deque;
+use physics::{Particle, PhysVec, ForceSolver, force, BOX_SIZE};
+use config::OpeningCriterion;
+use pool::ThreadPool;
+use std::{fmt, f64, mem};
+use std::rc::Rc;
 
 pub static mut THRESH : f64 = 1.0;
+pub static mut CRITERION : OpeningCriterion = OpeningCriterion::Geometric;
 
-struct Branch {
-    tl : Box<Node>,
-    tr : Box<Node>,
-    bl : Box<Node>,
-    br : Box<Node>
-}
+// the relative error criterion needs an acceleration to compare against, so on the
+// first step it falls back to classic Barnes-Hut with this opening angle
+static FIRST_STEP_THETA : f64 = 0.5;
 
-pub enum Node {
-    Many(BoxStats, Branch),
-    One(Particle),
-    Zero
-}
+// nodes this deep are not split any further (guards against coincident particles);
+// whatever is left in them is summed directly
+static MAX_DEPTH : u32 = 40;
+
+// build_par builds the tree serially down to this depth and shares the up to
+// 4^PAR_DEPTH subtrees below it out over the thread pool
+static PAR_DEPTH : u32 = 2;
+
+// short range TreePM forces are dropped beyond this many split scales
+static SPLIT_CUTOFF : f64 = 4.5;
+
+// the root is never anyone's child, so index 0 marks a leaf
+const NO_CHILD : usize = 0;
 
+#[derive(Clone, Copy)]
 struct BoxStats {
     pos: PhysVec,
     com: Particle,
     width: f64,
     height: f64,
+    bmax: f64,           // distance from centre of mass to furthest corner
     num_particles: u32
 }
 
+// In a periodic box these measure to the nearest image of the node
+impl BoxStats {
+    fn contains(&self, pos: &PhysVec) -> bool {
+        let d = self.pos.separation(*pos);
+        d.x.abs() <= self.width/2. && d.y.abs() <= self.height/2.
+    }
+
+    //distance from pos to the nearest point of the box
+    fn min_dist(&self, pos: &PhysVec) -> f64 {
+        let d = self.pos.separation(*pos);
+        let dx = f64::max(d.x.abs() - self.width/2., 0.);
+        let dy = f64::max(d.y.abs() - self.height/2., 0.);
+        (dx*dx + dy*dy).sqrt()
+    }
+
+    //whether the nearest images of everything in the node, seen from pos, lie in the
+    //same copy of it, as they must for its centre of mass to stand in for them
+    fn one_image(&self, pos: &PhysVec) -> bool {
+        let l = unsafe { BOX_SIZE };
+        if l == 0. { return true }
+        let d = self.pos.separation(*pos);
+        d.x.abs() + self.width/2. < l/2. && d.y.abs() + self.height/2. < l/2.
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Node {
+    stats: BoxStats,
+    start: usize,        // the node owns index[start..end]
+    end: usize,
+    child: usize         // first of four consecutive children (tl, tr, bl, br)
+}
+
+// A square region of the tree waiting to be built: node `ix`, owning index[start..end]
+#[derive(Clone, Copy)]
+struct Cell {
+    ix: usize,
+    start: usize,
+    end: usize,
+    x: f64,
+    y: f64,
+    half: f64
+}
+
+// Flat quadtree. Nodes live in one array and refer to their particles as a contiguous
+// range of `index`, which is partitioned in place while building. Both buffers are kept
+// between calls to `build` so a step reallocates nothing once they have grown.
 pub struct QuadTree {
-    root: Node,
+    nodes: Vec<Node>,
+    index: Vec<usize>,
+    subtrees: Vec<QuadTree>      // scratch trees reused by `build_par`
 }
 
 impl QuadTree {
-    pub fn new (particles: Vec<Particle>) -> QuadTree {
-        let (xmax, xmin, ymax, ymin) = find_bounding_box(&particles);
-        let x = xmax + xmin / 2.0;
-        let y = ymax + ymin / 2.0;
-        let mut xvar = xmax - xmin / 2.0;
-        let mut yvar = ymax - ymin / 2.0;
+    pub fn new (particles: &Vec<Particle>) -> QuadTree {
+        let mut qt = QuadTree { nodes: Vec::new(), index: Vec::new(), subtrees: Vec::new() };
+        qt.build(particles);
+        qt
+    }
+
+    pub fn build(&mut self, particles: &Vec<Particle>) {
+        match self.start_build(particles) {
+            Some(root) => self.make_node(particles, root, 0),
+            None => ()
+        }
+    }
+
+    // Build the top PAR_DEPTH levels here, then the subtrees below them concurrently on
+    // the pool, each into its own scratch tree, and finally graft them back in.
+    pub fn build_par(&mut self, particles: &Vec<Particle>, pool: &ThreadPool) {
+        let root = match self.start_build(particles) {
+            Some(root) => root,
+            None => return
+        };
+        let mut frontier = Vec::new();
+        self.make_top(particles, root, 0, &mut frontier);
+
+        let mut subtrees = mem::take(&mut self.subtrees);
+        while subtrees.len() < frontier.len() {
+            subtrees.push(QuadTree::new(&Vec::new()));
+        }
+        for (cell, sub) in frontier.iter().zip(subtrees.iter_mut()) {
+            sub.index.clear();
+            sub.index.extend(self.index[cell.start..cell.end].iter().cloned());
+        }
+        {
+            let frontier = &frontier;
+            pool.run_mut(&mut subtrees[..frontier.len()], 1, &|start, subs: &mut [QuadTree]| {
+                for (sub, cell) in subs.iter_mut().zip(frontier[start..].iter()) {
+                    let local = Cell { ix: 0, start: 0, end: cell.end - cell.start, .. *cell };
+                    sub.nodes.clear();
+                    sub.nodes.push(empty_node(local.x, local.y, local.half));
+                    sub.make_node(particles, local, PAR_DEPTH);
+                }
+            });
+        }
+        for (cell, sub) in frontier.iter().zip(subtrees.iter()) {
+            self.graft(cell, sub);
+        }
+        self.subtrees = subtrees;
+    }
+
+    //reset the buffers and return the root cell, or None if there is nothing to build
+    fn start_build(&mut self, particles: &Vec<Particle>) -> Option<Cell> {
+        self.nodes.clear();
+        self.index.clear();
+        self.index.extend(0..particles.len());
+        if particles.len() == 0 { return None }
+        let (xmax, xmin, ymax, ymin) = find_bounding_box(particles);
+        let x = (xmax + xmin) / 2.0;
+        let y = (ymax + ymin) / 2.0;
         // set to square
-        if xvar > yvar {
-            yvar = xvar
+        let half = f64::max(xmax - xmin, ymax - ymin) / 2.0;
+        self.nodes.push(empty_node(x, y, half));
+        Some(Cell { ix: 0, start: 0, end: particles.len(), x: x, y: y, half: half })
+    }
+
+    fn make_node(&mut self, particles: &Vec<Particle>, cell: Cell, depth: u32) {
+        match self.split_node(particles, cell, depth) {
+            Some(children) => for &c in children.iter() {
+                self.make_node(particles, c, depth+1)
+            },
+            None => ()
+        }
+    }
+
+    //as make_node, but stop at PAR_DEPTH and collect the cells still to be built
+    fn make_top(&mut self, particles: &Vec<Particle>, cell: Cell, depth: u32, frontier: &mut Vec<Cell>) {
+        if depth == PAR_DEPTH {
+            frontier.push(cell);
+            return
+        }
+        match self.split_node(particles, cell, depth) {
+            Some(children) => for &c in children.iter() {
+                self.make_top(particles, c, depth+1, frontier)
+            },
+            None => ()
+        }
+    }
+
+    //fill in the node for cell; if it needs splitting, partition it and return its children
+    fn split_node(&mut self, particles: &Vec<Particle>, cell: Cell, depth: u32) -> Option<[Cell; 4]> {
+        let Cell { ix, start, end, x, y, half } = cell;
+        let stats = calc_stats(particles, &self.index[start..end], x, y, half, half);
+        self.nodes[ix] = Node { stats: stats, start: start, end: end, child: NO_CHILD };
+        if end - start < 2 || depth >= MAX_DEPTH {
+            return None
+        }
+        let (s1, s2, s3) = self.partition(particles, start, end, x, y);
+        let child = self.nodes.len();
+        for _ in 0..4 {
+            self.nodes.push(empty_node(x, y, half));
+        }
+        self.nodes[ix].child = child;
+        let h = half / 2.;
+        Some([Cell { ix: child,     start: start, end: s1,  x: x-h, y: y+h, half: h },
+              Cell { ix: child + 1, start: s1,    end: s2,  x: x+h, y: y+h, half: h },
+              Cell { ix: child + 2, start: s2,    end: s3,  x: x-h, y: y-h, half: h },
+              Cell { ix: child + 3, start: s3,    end: end, x: x+h, y: y-h, half: h }])
+    }
+
+    //copy a subtree built for cell into place, shifting its node and index offsets
+    fn graft(&mut self, cell: &Cell, sub: &QuadTree) {
+        // sub's node k (k >= 1) ends up at base + k; its root replaces node cell.ix
+        let base = self.nodes.len() - 1;
+        for (k, node) in sub.nodes.iter().enumerate() {
+            let mut n = *node;
+            n.start += cell.start;
+            n.end += cell.start;
+            if n.child != NO_CHILD { n.child += base }
+            if k == 0 { self.nodes[cell.ix] = n } else { self.nodes.push(n) }
+        }
+        for (i, &j) in sub.index.iter().enumerate() {
+            self.index[cell.start + i] = j;
+        }
+    }
+
+    //reorder index[start..end] into tl, tr, bl, br runs and return the three boundaries
+    fn partition(&mut self, particles: &Vec<Particle>, start: usize, end: usize,
+                 xsplit: f64, ysplit: f64) -> (usize, usize, usize) {
+        let mid = start + split_by(&mut self.index[start..end], |p| particles[p].pos.y > ysplit);
+        let s1 = start + split_by(&mut self.index[start..mid], |p| particles[p].pos.x <= xsplit);
+        let s3 = mid + split_by(&mut self.index[mid..end], |p| particles[p].pos.x <= xsplit);
+        (s1, mid, s3)
+    }
+
+    pub fn force(&self, particles: &Vec<Particle>, p: &Particle) -> PhysVec {
+        if self.nodes.len() == 0 {
+            return PhysVec { x: 0., y: 0. }
+        }
+        self.bh_force(particles, p, 0, None)
+    }
+
+    //short range part of a TreePM split on scale rs; nodes out of range are never visited
+    pub fn force_short(&self, particles: &Vec<Particle>, p: &Particle, rs: f64) -> PhysVec {
+        if self.nodes.len() == 0 {
+            return PhysVec { x: 0., y: 0. }
+        }
+        self.bh_force(particles, p, 0, Some(rs))
+    }
+
+    //append the indices of the particles within radius of pos to out
+    pub fn neighbours(&self, particles: &Vec<Particle>, pos: &PhysVec, radius: f64, out: &mut Vec<usize>) {
+        if self.nodes.len() == 0 {
+            return
+        }
+        self.find_near(particles, pos, radius, 0, out)
+    }
+
+    fn find_near(&self, particles: &Vec<Particle>, pos: &PhysVec, radius: f64, ix: usize, out: &mut Vec<usize>) {
+        let node = &self.nodes[ix];
+        if node.stats.num_particles == 0 || node.stats.min_dist(pos) > radius {
+            return
+        }
+        if node.child == NO_CHILD {
+            for &j in &self.index[node.start..node.end] {
+                if pos.separation(particles[j].pos).modulus() <= radius { out.push(j) }
+            }
         } else {
-            xvar = yvar
+            for c in node.child..node.child+4 {
+                self.find_near(particles, pos, radius, c, out)
+            }
         }
-        QuadTree { root: make_node(particles, x, y, xvar, yvar) }
     }
 
-    pub fn force(&self, p: Particle) -> PhysVec {
-        bh_force(&p, &self.root).unwrap()
+    fn bh_force(&self, particles: &Vec<Particle>, p: &Particle, ix: usize, split: Option<f64>) -> PhysVec {
+        let node = &self.nodes[ix];
+        let mut tot_force = PhysVec { x: 0., y: 0. };
+        if node.stats.num_particles == 0 {
+            return tot_force
+        }
+        if let Some(rs) = split {
+            if node.stats.min_dist(&p.pos) > SPLIT_CUTOFF * rs { return tot_force }
+        }
+        if node.child == NO_CHILD {
+            for &j in &self.index[node.start..node.end] {
+                let q = &particles[j];
+                if q != p { tot_force.add(&split_force(p, q, split)) }
+            }
+        } else if accept_node(p, &node.stats) {
+            tot_force = split_force(p, &node.stats.com, split)
+        } else {
+            for c in node.child..node.child+4 {
+                tot_force.add(&self.bh_force(particles, p, c, split))
+            }
+        }
+        tot_force
     }
 }
 
+//move the entries for which pred holds to the front, returning how many there were
+pub fn split_by<F: Fn(usize) -> bool>(index: &mut [usize], pred: F) -> usize {
+    let mut split = 0;
+    for i in 0..index.len() {
+        if pred(index[i]) {
+            index.swap(i, split);
+            split += 1;
+        }
+    }
+    split
+}
+
+fn empty_node(x: f64, y: f64, half: f64) -> Node {
+    Node { stats: calc_stats(&Vec::new(), &[], x, y, half, half), start: 0, end: 0, child: NO_CHILD }
+}
+
 pub fn find_bounding_box(particles: &Vec<Particle>) -> (f64, f64, f64, f64) {
     let mut xmax: f64 = f64::NEG_INFINITY; let mut ymax = f64::NEG_INFINITY;
     let mut xmin = f64::INFINITY;     let mut ymin = f64::INFINITY;
@@ -64,172 +313,277 @@ pub fn find_bounding_box(particles: &Vec<Particle>) -> (f64, f64, f64, f64) {
     (xmax, xmin, ymax, ymin)
 }
 
-fn make_branch(particles: Vec<Particle>, x: f64, y: f64, xvar: f64, yvar: f64) -> Branch {
-    let (tla, tra, bla, bra) = partition(&particles, x, y);
-    Branch { 
-           tl: Box::new(make_node(tla, x-xvar/2., y+yvar/2., xvar/2., yvar/2.)),
-           tr: Box::new(make_node(tra, x+xvar/2., y+yvar/2., xvar/2., yvar/2.)),
-           bl: Box::new(make_node(bla, x-xvar/2., y-yvar/2., xvar/2., yvar/2.)),
-           br: Box::new(make_node(bra, x+xvar/2., y-yvar/2., xvar/2., yvar/2.)),
-    }
-}
-
-fn make_node(particles: Vec<Particle>, x: f64, y: f64, xvar: f64, yvar: f64) -> Node {
-    let n = particles.len();
-    if n > 1 { 
-        let stats = calc_stats(&particles, x, y, xvar, yvar);
-        return Node::Many(stats, make_branch(particles, x, y, xvar, yvar));
-    } else if n == 1 {
-        return Node::One(particles[0].clone());
-    } else {
-        return Node::Zero;
-    }
-}
-
-fn partition<'a>(particles: &Vec<Particle>, xsplit: f64, ysplit: f64) ->
-    (Vec<Particle>, Vec<Particle>, Vec<Particle>, Vec<Particle>) {
-    let mut tr: Vec<Particle> = Vec::new();
-    let mut br: Vec<Particle> = Vec::new();
-    let mut tl: Vec<Particle> = Vec::new();
-    let mut bl: Vec<Particle> = Vec::new();
-    for &p in particles {
-        if p.pos.x > xsplit {
-            if p.pos.y > ysplit {
-                tr.push(p)
-            } else {
-                br.push(p)
-            }
-        } else {
-            if p.pos.y > ysplit {
-                tl.push(p)
-            } else {
-                bl.push(p)
-            }
-        }
-    }
-    (tl, tr, bl, br)
-}
-
-fn calc_stats(particles: &Vec<Particle>, x: f64, y:f64, xvar:f64, yvar:f64) -> BoxStats {
+fn calc_stats(particles: &Vec<Particle>, index: &[usize], x: f64, y:f64, xvar:f64, yvar:f64) -> BoxStats {
     let mut xmass_sum = 0.;
     let mut ymass_sum = 0.;
     let mut mass = 0.;
     let mut num_pcls = 0;
-    for p in particles {
-        xmass_sum += p.pos.x*p.mass;
-        ymass_sum += p.pos.y*p.mass;
-        mass += p.mass;
+    for &i in index {
+        let p = &particles[i];
+        xmass_sum += p.pos.x*p.source_mass();
+        ymass_sum += p.pos.y*p.source_mass();
+        mass += p.source_mass();
         num_pcls += 1;
     }
+    let com = if mass > 0. {
+        PhysVec {x: xmass_sum/mass, y: ymass_sum/mass}
+    } else {
+        PhysVec {x: x, y: y}
+    };
+    let cornerx = (com.x - x).abs() + xvar;
+    let cornery = (com.y - y).abs() + yvar;
     BoxStats { pos: PhysVec { x: x, y: y },
-               com: Particle { 
-                   pos: PhysVec {x: xmass_sum/mass, y: ymass_sum/mass},
-                   vel: PhysVec {x: 0., y: 0.},
-                   mass: mass },
+               com: Particle::new(com, PhysVec {x: 0., y: 0.}, mass),
                width: xvar * 2.0,
                height: yvar * 2.0,
+               bmax: (cornerx*cornerx + cornery*cornery).sqrt(),
                num_particles: num_pcls
     }
 }
 
+// With the mesh Green's function filtered by exp(-k^2 rs^2), the mesh carries the force
+// of a Gaussian cloud, (m/r)(1 - exp(-r^2/4rs^2)) for our 1/r law, so the tree supplies
+// the rest: the Newtonian force times exp(-r^2/4rs^2). (In 3D the same split gives the
+// familiar erfc kernel; in 2D it reduces to this Gaussian.)
+fn split_force(p: &Particle, q: &Particle, split: Option<f64>) -> PhysVec {
+    let f = force(p, q);
+    match split {
+        None => f,
+        Some(rs) => {
+            let d = p.pos.separation(q.pos);
+            let s = (-(d.x*d.x + d.y*d.y) / (4.*rs*rs)).exp();
+            PhysVec { x: f.x * s, y: f.y * s }
+        }
+    }
+}
+
+//decide whether the node summarised by stats can stand in for its contents
+fn accept_node(p: &Particle, stats: &BoxStats) -> bool {
+    if !stats.one_image(&p.pos) { return false }
+    let dist = p.pos.separation(stats.com.pos).modulus();
+    accept(dist, stats.width, stats.bmax, stats.com.mass/dist, p.acc.modulus(), stats.contains(&p.pos))
+}
 
-pub fn bh_force(p: &Particle, node: &Node) -> Option<PhysVec> {
-    match *node {
-        Node::One(p2) => if *p == p2 { return None } else { return Some(force(p, &p2)) } ,
-        Node::Zero    => return None,
-        Node::Many(ref stats,ref branch) => {
-            if unsafe { p.pos.diff(stats.com.pos).modulus()/stats.width > THRESH } {
-                return Some(force(p, &stats.com))
-            } else {
-                return Some(force_branch(p, branch))
+// The opening test shared by the quad- and octrees. `monopole` is the acceleration the
+// node's mass would cause at `dist`, and `amod` the particle's acceleration last step.
+pub fn accept(dist: f64, width: f64, bmax: f64, monopole: f64, amod: f64, inside: bool) -> bool {
+    unsafe {
+        match CRITERION {
+            OpeningCriterion::Geometric => dist/width > THRESH,
+            // the classic theta, kept as a spelling of Geometric with threshold 1/theta
+            OpeningCriterion::BarnesHut => dist/width > 1./THRESH,
+            OpeningCriterion::Bmax      => dist > bmax/THRESH,
+            OpeningCriterion::RelativeError => {
+                if amod == 0. {
+                    width/dist < FIRST_STEP_THETA
+                } else {
+                    // leading error term of the monopole is ~ monopole * (l/d)^2
+                    let ratio = width/dist;
+                    !inside && monopole*ratio*ratio <= THRESH*amod
+                }
             }
         }
     }
 }
 
-fn force_branch(p: &Particle, branch: &Branch) -> PhysVec {
-    let mut tot_force = PhysVec { x: 0., y: 0. };
-    match bh_force(p, &branch.tl) {
-        Some(v) => tot_force.add(&v),
-        None => ()
+
+pub struct BarnesHut {
+    tree: QuadTree
+}
+
+impl BarnesHut {
+    pub fn new() -> BarnesHut {
+        BarnesHut { tree: QuadTree::new(&Vec::new()) }
+    }
+}
+
+impl ForceSolver for BarnesHut {
+    fn forces(&mut self, particles: &Vec<Particle>, frcs: &mut Vec<PhysVec>) {
+        self.tree.build(particles);
+        for (p, f) in particles.iter().zip(frcs.iter_mut()) {
+            *f = self.tree.force(particles, p);
+        }
+    }
+
+    fn forces_active(&mut self, particles: &Vec<Particle>, active: &Vec<bool>, frcs: &mut Vec<PhysVec>) {
+        self.tree.build(particles);
+        for ((p, f), &a) in particles.iter().zip(frcs.iter_mut()).zip(active.iter()) {
+            if a { *f = self.tree.force(particles, p) }
+        }
     }
-    match bh_force(p, &branch.tr) {
-        Some(v) => tot_force.add(&v),
-        None => ()
+}
+
+pub struct BarnesHutParallel {
+    tree: QuadTree,
+    pool: Rc<ThreadPool>
+}
+
+impl BarnesHutParallel {
+    pub fn new(pool: Rc<ThreadPool>) -> BarnesHutParallel {
+        BarnesHutParallel { tree: QuadTree::new(&Vec::new()), pool: pool }
     }
-    match bh_force(p, &branch.bl) {
-        Some(v) => tot_force.add(&v),
-        None => ()
+}
+
+impl ForceSolver for BarnesHutParallel {
+    fn forces(&mut self, particles: &Vec<Particle>, frcs: &mut Vec<PhysVec>) {
+        self.tree.build_par(particles, &self.pool);
+        let tree = &self.tree;
+        self.pool.run_mut(frcs, self.pool.chunk_for(particles.len()), &|start, out: &mut [PhysVec]| {
+            for (f, p) in out.iter_mut().zip(particles[start..].iter()) {
+                *f = tree.force(particles, p);
+            }
+        });
     }
-    match bh_force(p, &branch.br) {
-        Some(v) => tot_force.add(&v),
-        None => ()
+
+    fn forces_active(&mut self, particles: &Vec<Particle>, active: &Vec<bool>, frcs: &mut Vec<PhysVec>) {
+        self.tree.build_par(particles, &self.pool);
+        let tree = &self.tree;
+        self.pool.run_mut(frcs, self.pool.chunk_for(particles.len()), &|start, out: &mut [PhysVec]| {
+            for (i, f) in (start..).zip(out.iter_mut()) {
+                if active[i] { *f = tree.force(particles, &particles[i]) }
+            }
+        });
     }
-    tot_force
 }
 
-pub fn pcl_pointers<'a>(particles: &'a Vec<Particle>) -> Vec<&'a Particle> {
-    let mut v : Vec<&Particle> = Vec::with_capacity(particles.len());
-    for p in particles {
-        v.push(p)
+impl fmt::Display for BoxStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "X:{} Y:{} Width:{} Height:{} Mass:{} NumPcls: {}",
+        self.pos.x, self.pos.y, self.width, self.height, self.com.mass, self.num_particles)
     }
-    v
 }
 
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use config::OpeningCriterion;
+    use physics::tests::{lock, random_particles, exact_forces, forces_of, rel_errors};
+    use pool::ThreadPool;
+    use physics::{Particle, PhysVec, BOX_SIZE};
+    use super::{QuadTree, BarnesHut, BarnesHutParallel, THRESH, CRITERION, find_bounding_box};
 
-pub fn stepsim(particles: &mut Vec<Particle>) {
-    let mut frcs : Vec<PhysVec> =  Vec::with_capacity(particles.len());
-    {
-        let qt = QuadTree::new(particles.clone());
-        for &p in particles.iter() {
-            frcs.push(qt.force(p));
+    //mean force error against direct summation for each threshold in turn
+    fn errors(criterion: OpeningCriterion, thresholds: &[f64]) -> Vec<f64> {
+        let mut particles = random_particles(400, 100.);
+        let exact = exact_forces(&particles);
+        for (p, f) in particles.iter_mut().zip(exact.iter()) {
+            p.acc.x = f.x / p.mass;
+            p.acc.y = f.y / p.mass;
         }
+        unsafe { CRITERION = criterion }
+        thresholds.iter().map(|&t| {
+            unsafe { THRESH = t }
+            rel_errors(&forces_of(&mut BarnesHut::new(), &particles), &exact).0
+        }).collect()
     }
-    for (mut p, &f) in particles.iter_mut().zip(frcs.iter()) {
-        stepvel(p, f, true);
-        p.steppos();
+
+    //thresholds go from loose to strict, so the error must fall at each
+    fn assert_falls(errs: Vec<f64>) {
+        for w in errs.windows(2) {
+            assert!(w[1] < w[0], "error did not fall: {:?}", errs);
+        }
+        assert!(errs[errs.len() - 1] < 1e-3, "strictest error too large: {:?}", errs);
     }
-}
 
-pub fn stepsim_par(particles: &mut Vec<Particle>) {
-    let lenp = particles.len();
-    let rcqt = Arc::new(QuadTree::new(particles.clone()));
+    #[test]
+    fn bounding_box() {
+        let particles: Vec<Particle> = (0..100).map(|i| {
+            Particle::new(PhysVec { x: i as f64, y: i as f64 + 1.5 }, PhysVec { x: 0., y: 0. }, 1.)
+        }).collect();
+        assert_eq!(find_bounding_box(&particles), (99., 0., 100.5, 1.5));
+    }
 
-    let (tx, rx) = mpsc::channel();              //channel to receive results
-    let pool = deque::BufferPool::new();   //work pool
-    let (worker, stealer) = pool.deque();
+    #[test]
+    fn error_bound_against_classical() {
+        let _g = lock();
+        let particles = random_particles(1000, 100.);
+        let exact = exact_forces(&particles);
+        // mean relative error at the default geometric threshold and a strict one; single
+        // particles whose forces nearly cancel can be much worse, so the max isn't bounded
+        for &(thresh, bound) in [(1., 3e-2), (4., 2e-3)].iter() {
+            unsafe { THRESH = thresh }
+            let (mean, _) = rel_errors(&forces_of(&mut BarnesHut::new(), &particles), &exact);
+            assert!(mean < bound, "threshold {}: mean error {}", thresh, mean);
+        }
+    }
+
+    #[test]
+    fn fully_opened_tree_is_direct() {
+        let _g = lock();
+        // no node is ever far enough away to stand in for its contents
+        unsafe { THRESH = 1e9 }
+        let particles = random_particles(300, 100.);
+        let (_, max) = rel_errors(&forces_of(&mut BarnesHut::new(), &particles), &exact_forces(&particles));
+        assert!(max < 1e-9, "max error {}", max);
+    }
 
-    for (ix, &p) in particles.iter().enumerate() {
-        worker.push((ix as u32, p.clone()))             //construct queue
+    #[test]
+    fn parallel_build_matches_serial() {
+        let _g = lock();
+        let particles = random_particles(2000, 100.);
+        let serial = forces_of(&mut BarnesHut::new(), &particles);
+        let parallel = forces_of(&mut BarnesHutParallel::new(Rc::new(ThreadPool::new(4))), &particles);
+        for (s, p) in serial.iter().zip(parallel.iter()) {
+            assert!(s.diff(*p).modulus() <= 1e-12 * s.modulus());
+        }
     }
 
-    for _ in 0..7 {
-        let localqt = rcqt.clone();
-        let localtx = tx.clone();
-        let stlr = stealer.clone();
-        thread::spawn(move || { steal_work(localqt, &localtx, stlr) });
+    #[test]
+    fn periodic_neighbours_and_forces() {
+        let _g = lock();
+        unsafe { BOX_SIZE = 100. }
+        let mut particles = random_particles(400, 100.);
+        for p in particles.iter_mut() { p.pos.wrap() }
+        particles[0].pos = PhysVec { x: 49.5, y: 10. };
+        particles[1].pos = PhysVec { x: -49.5, y: 10.5 };
+        let mut near = Vec::new();
+        QuadTree::new(&particles).neighbours(&particles, &particles[0].pos, 2., &mut near);
+        assert!(near.contains(&1));
+        // nodes spanning a boundary must be opened rather than summarised by their mass
+        let exact = exact_forces(&particles);
+        unsafe { THRESH = 1e9 }
+        let (_, max) = rel_errors(&forces_of(&mut BarnesHut::new(), &particles), &exact);
+        assert!(max < 1e-9, "fully opened max error {}", max);
+        // nearest image forces in a uniform box mostly cancel, so relative errors run high
+        unsafe { THRESH = 4. }
+        let (mean, _) = rel_errors(&forces_of(&mut BarnesHut::new(), &particles), &exact);
+        assert!(mean < 1.5e-2, "mean error {}", mean);
     }
-    for _ in 0..lenp {
-        let (ix, pv) = rx.recv().unwrap();
-        let p = &mut particles[ix as usize];
-        stepvel(p, pv, true);
-        p.steppos();
+
+    #[test]
+    fn geometric_error_falls() {
+        let _g = lock();
+        assert_falls(errors(OpeningCriterion::Geometric, &[0.5, 1., 2., 6.]));
+    }
+
+    #[test]
+    fn barnes_hut_error_falls() {
+        let _g = lock();
+        assert_falls(errors(OpeningCriterion::BarnesHut, &[1., 0.5, 0.25, 0.1]));
     }
-}
 
-fn steal_work(qt: Arc<QuadTree>, tx: &mpsc::Sender<(u32, PhysVec)>, stealer: deque::Stealer<(u32, Particle)>) {
-    loop {
-        match stealer.steal() {
-            deque::Stolen::Empty => break,
-            deque::Stolen::Abort => continue,
-            deque::Stolen::Data((ix,p)) => {tx.send((ix,qt.force(p))); ()}
+    #[test]
+    fn barnes_hut_is_geometric_inverted() {
+        let _g = lock();
+        let particles = random_particles(500, 100.);
+        unsafe { CRITERION = OpeningCriterion::BarnesHut; THRESH = 0.5 };
+        let theta = forces_of(&mut BarnesHut::new(), &particles);
+        unsafe { CRITERION = OpeningCriterion::Geometric; THRESH = 2. };
+        let geometric = forces_of(&mut BarnesHut::new(), &particles);
+        for (a, b) in theta.iter().zip(geometric.iter()) {
+            assert_eq!((a.x, a.y), (b.x, b.y));
         }
     }
-}
 
-impl fmt::Display for BoxStats {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "X:{} Y:{} Width:{} Height:{} Mass:{} NumPcls: {}",
-        self.pos.x, self.pos.y, self.width, self.height, self.com.mass, self.num_particles)
+    #[test]
+    fn bmax_error_falls() {
+        let _g = lock();
+        assert_falls(errors(OpeningCriterion::Bmax, &[1., 0.5, 0.25, 0.1]));
+    }
+
+    #[test]
+    fn relative_error_falls() {
+        let _g = lock();
+        assert_falls(errors(OpeningCriterion::RelativeError, &[1e-1, 1e-2, 1e-3, 1e-5]));
     }
 }
@@ -0,0 +1,236 @@
+use barneshut::QuadTree;
+use collide::{Merger, combine};
+use config::Species;
+use physics::{Particle, PhysVec};
+use rand;
+use std::f64;
+
+// Sub-grid physics for black hole particles, applied after each gravity step (2D only).
+// The background around each hole - surface density, mean velocity and dispersion - is
+// measured from the particles within the friction radius.
+//
+// Dynamical friction is Chandrasekhar's argument redone for the 1/r force law. A field
+// particle passing at relative speed v is deflected by the same angle pi M / v^2 whatever
+// its impact parameter b, so there is no Coulomb logarithm: the drag grows linearly with
+// the range of b, which is cut off at b_max = friction radius. For a hole much heavier
+// than the field
+//     dv/dt = -pi^2 M Sigma b_max / v^2 * (1 - exp(-X^2)),    X = v / (sqrt(2) sigma)
+// where, as in the 3D formula, only field particles slower than the hole are counted;
+// 1 - exp(-X^2) is that fraction for a 2D Maxwellian. The impulse taken from the hole is
+// shared out over the sampled neighbours, so momentum is conserved.
+//
+// Accretion is the 2D analogue of Bondi-Hoyle: gas crossing a strip of width 2 r_acc at
+// w = sqrt(c^2 + v^2) is captured in proportion to the square of its deflection, giving
+//     dM/dt = 2 pi^2 r_acc Sigma M^2 / w^3
+// capped at the geometric rate 2 r_acc Sigma w, with c^2 the two dimensional dispersion.
+// Each step every bound particle within r_acc is swallowed with the probability that
+// makes the expected swallowed mass dM/dt dt. Two holes closer than the capture radius
+// merge. Mass and momentum are conserved by both.
+
+pub struct BlackHoles {
+    tree: QuadTree,
+    near: Vec<usize>,
+    friction: bool,
+    friction_radius: f64,
+    accretion_radius: f64,     // 0 turns accretion off
+    capture_radius: f64        // 0 turns hole mergers off
+}
+
+//the field around a hole
+struct Background {
+    sigma: f64,                // one dimensional dispersion
+    density: f64,              // surface density
+    mean: PhysVec
+}
+
+impl BlackHoles {
+    pub fn new(friction: bool, friction_radius: f64, accretion_radius: f64, capture_radius: f64) -> BlackHoles {
+        BlackHoles { tree: QuadTree::new(&Vec::new()), near: Vec::new(), friction: friction,
+                     friction_radius: friction_radius, accretion_radius: accretion_radius,
+                     capture_radius: capture_radius }
+    }
+
+    //apply friction over dt, then accretion and mergers, returning what was absorbed
+    pub fn step(&mut self, particles: &mut Vec<Particle>, dt: f64) -> Vec<Merger> {
+        let mut events = Vec::new();
+        let holes: Vec<usize> = (0..particles.len()).filter(|&i| particles[i].kind == Species::BlackHole).collect();
+        if holes.len() == 0 { return events }
+        self.tree.build(particles);
+        let mut gone = vec![false; particles.len()];
+        for &b in &holes {
+            if gone[b] { continue }
+            let field = self.background(particles, b, &gone);
+            if self.friction {
+                if let Some(ref f) = field { self.drag(particles, b, f, dt) }
+            }
+            let reach = f64::max(self.accretion_radius, self.capture_radius);
+            if reach == 0. { continue }
+            self.near.clear();
+            let pos = particles[b].pos;
+            self.tree.neighbours(particles, &pos, reach, &mut self.near);
+            let chance = match field {
+                Some(ref f) if self.accretion_radius > 0. => self.swallow_chance(particles, b, f, &gone, dt),
+                _ => 0.
+            };
+            // candidates for accretion are judged against the hole as it was before it ate
+            let start = particles[b];
+            for &j in &self.near {
+                if j == b || gone[j] { continue }
+                let (hole, q) = (particles[b], particles[j]);
+                let dist = hole.pos.separation(q.pos).modulus();
+                let (keep, lose) = match q.kind {
+                    Species::BlackHole => {
+                        if dist > self.capture_radius { continue }
+                        if q.mass > hole.mass { (j, b) } else { (b, j) }
+                    },
+                    Species::Test => continue,
+                    _ => {
+                        if start.pos.separation(q.pos).modulus() > self.accretion_radius || !bound(&start, &q) { continue }
+                        if rand::random::<f64>() >= chance { continue }
+                        (b, j)
+                    }
+                };
+                particles[keep] = combine(&particles[keep], &particles[lose]);
+                gone[lose] = true;
+                events.push(Merger { survivor: particles[keep].id, absorbed: particles[lose].id,
+                                     mass: particles[keep].mass, pos: particles[keep].pos });
+                if lose == b { break }
+            }
+        }
+        if events.len() > 0 {
+            let mut k = 0;
+            particles.retain(|_| { k += 1; !gone[k - 1] });
+        }
+        events
+    }
+
+    //measure the field within the friction radius, leaving its members in self.near
+    fn background(&mut self, particles: &Vec<Particle>, b: usize, gone: &Vec<bool>) -> Option<Background> {
+        self.near.clear();
+        let pos = particles[b].pos;
+        self.tree.neighbours(particles, &pos, self.friction_radius, &mut self.near);
+        self.near.retain(|&j| !gone[j] && particles[j].kind != Species::BlackHole && particles[j].kind != Species::Test);
+        let (mut mass, mut mom, mut v2) = (0., PhysVec { x: 0., y: 0. }, 0.);
+        for &j in &self.near {
+            let q = &particles[j];
+            mass += q.mass;
+            mom.x += q.mass * q.vel.x;
+            mom.y += q.mass * q.vel.y;
+            v2 += q.mass * q.vel.dot(&q.vel);
+        }
+        if mass == 0. { return None }
+        let mean = PhysVec { x: mom.x / mass, y: mom.y / mass };
+        // one dimensional dispersion, from the mean square speed about the mean
+        let sigma = (f64::max(v2 / mass - mean.dot(&mean), 0.) / 2.).sqrt();
+        let density = mass / (f64::consts::PI * self.friction_radius * self.friction_radius);
+        Some(Background { sigma: sigma, density: density, mean: mean })
+    }
+
+    //slow the hole relative to the field in self.near, and give the field the impulse
+    fn drag(&self, particles: &mut Vec<Particle>, b: usize, field: &Background, dt: f64) {
+        let hole = particles[b];
+        let u = field.mean.diff(hole.vel);
+        let v = u.modulus();
+        if v == 0. { return }
+        let slower = if field.sigma > 0. {
+            let x = v / (f64::consts::SQRT_2 * field.sigma);
+            1. - (-x * x).exp()
+        } else { 1. };
+        let decel = f64::consts::PI * f64::consts::PI * hole.mass * field.density * self.friction_radius * slower / (v * v);
+        // friction can stop the hole relative to its surroundings but never reverse it
+        let dv = f64::min(decel * dt, v);
+        let mass: f64 = self.near.iter().map(|&j| particles[j].mass).sum();
+        let kick = hole.mass * dv / mass;
+        particles[b].vel.x -= u.x / v * dv;
+        particles[b].vel.y -= u.y / v * dv;
+        for &j in &self.near {
+            particles[j].vel.x += u.x / v * kick;
+            particles[j].vel.y += u.y / v * kick;
+        }
+    }
+
+    //probability of swallowing each bound particle in self.near within the accretion radius
+    fn swallow_chance(&self, particles: &Vec<Particle>, b: usize, field: &Background, gone: &Vec<bool>, dt: f64) -> f64 {
+        let hole = &particles[b];
+        let u = field.mean.diff(hole.vel);
+        let w2 = 2. * field.sigma * field.sigma + u.dot(&u);
+        if w2 == 0. { return 0. }
+        let w = w2.sqrt();
+        let strip = 2. * self.accretion_radius * field.density;
+        let rate = f64::min(f64::consts::PI * f64::consts::PI * strip * hole.mass * hole.mass / (w2 * w), strip * w);
+        let mut avail = 0.;
+        for &j in &self.near {
+            let q = &particles[j];
+            if j == b || gone[j] || q.kind == Species::BlackHole || q.kind == Species::Test { continue }
+            if hole.pos.separation(q.pos).modulus() <= self.accretion_radius && bound(hole, q) {
+                avail += q.mass;
+            }
+        }
+        if avail == 0. { 0. } else { f64::min(rate * dt / avail, 1.) }
+    }
+}
+
+//bound to the hole: relative speed below sqrt(2) times the circular speed, which for
+//the 1/r force law is sqrt(M) at any radius
+fn bound(hole: &Particle, q: &Particle) -> bool {
+    let dv = hole.vel.diff(q.vel);
+    dv.dot(&dv) < 2. * hole.mass
+}
+
+#[cfg(test)]
+mod tests {
+    use physics::{Particle, PhysVec};
+    use physics::tests::lock;
+    use config::Species;
+    use rand;
+    use std::f64;
+    use super::BlackHoles;
+
+    //a hole at rest among a grid of light particles with Gaussian velocities
+    fn field(hole_mass: f64, hole_vel: f64, sigma: f64) -> Vec<Particle> {
+        let mut particles = Vec::new();
+        for i in 0..40 {
+            for j in 0..40 {
+                let (r, t) = ((-2. * rand::random::<f64>().ln()).sqrt() * sigma, rand::random::<f64>() * 2. * f64::consts::PI);
+                let mut p = Particle::new(PhysVec { x: i as f64 * 2. - 39., y: j as f64 * 2. - 39. },
+                                          PhysVec { x: r * t.cos(), y: r * t.sin() }, 0.05);
+                p.id = particles.len() as u32;
+                particles.push(p);
+            }
+        }
+        let mut hole = Particle::new(PhysVec { x: 0., y: 0. }, PhysVec { x: hole_vel, y: 0. }, hole_mass);
+        hole.kind = Species::BlackHole;
+        hole.id = particles.len() as u32;
+        particles.push(hole);
+        particles
+    }
+
+    fn momentum(particles: &Vec<Particle>) -> PhysVec {
+        particles.iter().fold(PhysVec { x: 0., y: 0. }, |m, p| PhysVec { x: m.x + p.mass * p.vel.x, y: m.y + p.mass * p.vel.y })
+    }
+
+    #[test]
+    fn friction_conserves_momentum() {
+        let _g = lock();
+        let mut particles = field(10., 3., 0.3);
+        let before = momentum(&particles);
+        BlackHoles::new(true, 30., 0., 0.).step(&mut particles, 0.1);
+        let after = momentum(&particles);
+        let v = particles.last().unwrap().vel.x;
+        assert!(v > 0. && v < 3.);
+        assert!((after.x - before.x).abs() < 1e-9 && (after.y - before.y).abs() < 1e-9);
+    }
+
+    #[test]
+    fn accretion_rate_goes_as_mass_squared() {
+        let _g = lock();
+        // light holes in a hot field, where capture is well below the geometric limit
+        let swallowed = |mass: f64| (0..200).map(|_| {
+            let mut particles = field(mass, 0., 1.);
+            let mut holes = BlackHoles::new(false, 40., 20., 0.);
+            holes.step(&mut particles, 1.).len()
+        }).sum::<usize>() as f64;
+        let ratio = swallowed(0.4) / swallowed(0.2);
+        assert!(ratio > 3. && ratio < 5., "ratio {}", ratio);
+    }
+}
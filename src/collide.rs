@@ -0,0 +1,253 @@
+use physics::{Particle, PhysVec};
+use barneshut::QuadTree;
+use config::Species;
+use std::f64;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+// Inelastic mergers. Particles that come within a fixed radius of each other are replaced
+// by one body carrying their combined mass and momentum at their centre of mass, which
+// stops the force between them blowing up. The heavier body survives, so a galaxy's
+// central mass keeps its id as it accretes. A merged body sits at its pair's centre of
+// mass, which can bring it within reach of others, so merging goes on in passes over a
+// freshly built tree, each particle merging at most once a pass, until a pass finds nothing.
+//
+// Hard spheres. Particles with a radius bounce off each other when they overlap while
+// approaching, exchanging an impulse along the line of centres that conserves momentum
+// and scales their relative normal velocity by the coefficient of restitution. Overlaps
+// are also pushed apart so slow pairs do not sink into each other.
+//
+// Test particles are only there to be moved by gravity, so they neither merge nor bounce.
+
+pub struct Merger {
+    pub survivor: u32,
+    pub absorbed: u32,
+    pub mass: f64,          // of the survivor after the merger
+    pub pos: PhysVec
+}
+
+pub struct Collisions {
+    tree: QuadTree,
+    near: Vec<usize>,
+    touched: Vec<bool>      // merged this pass, so not where the tree thinks
+}
+
+// Record of the mergers in a run, from collisions and black holes alike. They are listed
+// in a file if one was given; either way they are counted for the summary at the end.
+pub struct MergeLog {
+    out: Option<BufWriter<File>>,
+    pub count: u32
+}
+
+impl MergeLog {
+    pub fn new(path: Option<&str>) -> MergeLog {
+        let out = path.map(|path| {
+            let mut out = BufWriter::new(File::create(Path::new(path)).unwrap());
+            writeln!(out, "# time cause survivor absorbed mass posx posy").unwrap();
+            out
+        });
+        MergeLog { out: out, count: 0 }
+    }
+
+    pub fn record(&mut self, time: f64, cause: &str, m: &Merger) {
+        self.count += 1;
+        match self.out {
+            Some(ref mut out) => writeln!(out, "{} {} {} {} {} {} {}", time, cause, m.survivor, m.absorbed,
+                                          m.mass, m.pos.x, m.pos.y).unwrap(),
+            None => ()
+        }
+    }
+}
+
+impl Collisions {
+    pub fn new() -> Collisions {
+        Collisions { tree: QuadTree::new(&Vec::new()), near: Vec::new(), touched: Vec::new() }
+    }
+
+    //merge everything closer than radius, returning what merged with what
+    pub fn merge(&mut self, particles: &mut Vec<Particle>, radius: f64) -> Vec<Merger> {
+        let mut events = Vec::new();
+        while self.merge_pass(particles, radius, &mut events) > 0 {}
+        events
+    }
+
+    fn merge_pass(&mut self, particles: &mut Vec<Particle>, radius: f64, events: &mut Vec<Merger>) -> usize {
+        let before = events.len();
+        self.tree.build(particles);
+        self.touched.clear();
+        self.touched.resize(particles.len(), false);
+        let mut gone = vec![false; particles.len()];
+        for i in 0..particles.len() {
+            if self.touched[i] || particles[i].kind == Species::Test { continue }
+            self.near.clear();
+            let pos = particles[i].pos;
+            self.tree.neighbours(particles, &pos, radius, &mut self.near);
+            for &j in &self.near {
+                if j == i || self.touched[j] || particles[j].kind == Species::Test { continue }
+                let (keep, lose) = if particles[j].mass > particles[i].mass { (j, i) } else { (i, j) };
+                particles[keep] = combine(&particles[keep], &particles[lose]);
+                gone[lose] = true;
+                self.touched[i] = true;
+                self.touched[j] = true;
+                events.push(Merger { survivor: particles[keep].id, absorbed: particles[lose].id,
+                                     mass: particles[keep].mass, pos: particles[keep].pos });
+                break
+            }
+        }
+        let merged = events.len() - before;
+        if merged > 0 {
+            let mut k = 0;
+            particles.retain(|_| { k += 1; !gone[k - 1] });
+        }
+        merged
+    }
+
+    //resolve contacts between hard spheres, returning how many bounced
+    pub fn bounce(&mut self, particles: &mut Vec<Particle>, restitution: f64) -> u32 {
+        let rmax = particles.iter().fold(0., |r, p| f64::max(r, p.radius));
+        if rmax == 0. { return 0 }
+        let mut count = 0;
+        self.tree.build(particles);
+        for i in 0..particles.len() {
+            if particles[i].radius == 0. || particles[i].kind == Species::Test { continue }
+            self.near.clear();
+            let pos = particles[i].pos;
+            self.tree.neighbours(particles, &pos, particles[i].radius + rmax, &mut self.near);
+            for &j in &self.near {
+                // every pair once; pairs with a point mass in them are handled from its partner
+                if j == i || (j < i && particles[j].radius > 0.) || particles[j].kind == Species::Test { continue }
+                if collide(particles, i, j, restitution) { count += 1 }
+            }
+        }
+        count
+    }
+}
+
+fn collide(particles: &mut Vec<Particle>, i: usize, j: usize, restitution: f64) -> bool {
+    let (a, b) = (particles[i], particles[j]);
+    let disp = a.pos.separation(b.pos);
+    let dist = disp.modulus();
+    let overlap = a.radius + b.radius - dist;
+    if overlap <= 0. || dist == 0. { return false }
+    let n = PhysVec { x: disp.x / dist, y: disp.y / dist };
+    let closing = (a.vel.x - b.vel.x) * n.x + (a.vel.y - b.vel.y) * n.y;
+    let (wa, wb) = (b.mass / (a.mass + b.mass), a.mass / (a.mass + b.mass));
+    // separate along the normal, keeping the centre of mass where it is
+    particles[i].pos.x -= n.x * overlap * wa;
+    particles[i].pos.y -= n.y * overlap * wa;
+    particles[j].pos.x += n.x * overlap * wb;
+    particles[j].pos.y += n.y * overlap * wb;
+    particles[i].pos.wrap();
+    particles[j].pos.wrap();
+    if closing <= 0. { return false }
+    let dv = (1. + restitution) * closing;
+    particles[i].vel.x -= n.x * dv * wa;
+    particles[i].vel.y -= n.y * dv * wa;
+    particles[j].vel.x += n.x * dv * wb;
+    particles[j].vel.y += n.y * dv * wb;
+    true
+}
+
+//a keeps its id; everything else is mass weighted
+pub fn combine(a: &Particle, b: &Particle) -> Particle {
+    let m = a.mass + b.mass;
+    let avg = |u: &PhysVec, v: &PhysVec| PhysVec { x: (u.x * a.mass + v.x * b.mass) / m,
+                                                   y: (u.y * a.mass + v.y * b.mass) / m };
+    let mut p = *a;
+    // b's nearest image, so pairs across a periodic boundary meet there and not mid-box
+    let mut bpos = a.pos;
+    bpos.add(&a.pos.separation(b.pos));
+    p.pos = avg(&a.pos, &bpos);
+    p.pos.wrap();
+    p.vel = avg(&a.vel, &b.vel);
+    p.acc = avg(&a.acc, &b.acc);
+    p.mass = m;
+    p.radius = (a.radius * a.radius + b.radius * b.radius).sqrt();
+    // the merged body takes the shorter of the two block steps
+    if b.level > p.level { p.level = b.level }
+    p
+}
+
+#[cfg(test)]
+mod tests {
+    use physics::{Particle, PhysVec};
+    use physics::tests::lock;
+    use config::Species;
+    use super::Collisions;
+
+    #[test]
+    fn merges_chain_within_a_frame() {
+        let _g = lock();
+        // c is out of reach of a and b, but not of the body they merge into
+        let mut particles: Vec<Particle> = [(0., 0., 1.), (0.9, 0., -1.), (0.45, 0.95, 3.)].iter().enumerate().map(|(i, &(x, y, vx))| {
+            let mut p = Particle::new(PhysVec { x: x, y: y }, PhysVec { x: vx, y: 0. }, 1.);
+            p.id = i as u32;
+            p
+        }).collect();
+        let events = Collisions::new().merge(&mut particles, 1.);
+        assert!(events.len() == 2 && particles.len() == 1);
+        assert!((particles[0].mass - 3.).abs() < 1e-12);
+        assert!((particles[0].vel.x - 1.).abs() < 1e-12);
+    }
+
+    #[test]
+    fn bounce_applies_restitution() {
+        let _g = lock();
+        for &e in &[1., 0.5, 0.] {
+            // an oblique hit between unequal, overlapping spheres
+            let mut a = Particle::new(PhysVec { x: 0., y: 0. }, PhysVec { x: 1., y: 0.3 }, 1.);
+            let mut b = Particle::new(PhysVec { x: 1.5, y: 0.5 }, PhysVec { x: -0.5, y: 0. }, 3.);
+            a.radius = 1.;
+            b.radius = 1.2;
+            b.id = 1;
+            let mut particles = vec![a, b];
+            assert_eq!(Collisions::new().bounce(&mut particles, e), 1);
+            let (c, d) = (particles[0], particles[1]);
+            // the normal is taken before the pair is pushed apart along it
+            let disp = a.pos.separation(b.pos);
+            let n = PhysVec { x: disp.x / disp.modulus(), y: disp.y / disp.modulus() };
+            let t = PhysVec { x: -n.y, y: n.x };
+            let rel = |p: &Particle, q: &Particle, u: &PhysVec| (p.vel.x - q.vel.x) * u.x + (p.vel.y - q.vel.y) * u.y;
+            assert!((rel(&c, &d, &n) + e * rel(&a, &b, &n)).abs() < 1e-12, "restitution {}", e);
+            assert!((rel(&c, &d, &t) - rel(&a, &b, &t)).abs() < 1e-12);
+            // momentum and the centre of mass are untouched
+            assert!((c.mass * c.vel.x + d.mass * d.vel.x - (a.mass * a.vel.x + b.mass * b.vel.x)).abs() < 1e-12);
+            assert!((c.mass * c.vel.y + d.mass * d.vel.y - (a.mass * a.vel.y + b.mass * b.vel.y)).abs() < 1e-12);
+            assert!((c.mass * c.pos.x + d.mass * d.pos.x - (a.mass * a.pos.x + b.mass * b.pos.x)).abs() < 1e-12);
+            assert!((c.mass * c.pos.y + d.mass * d.pos.y - (a.mass * a.pos.y + b.mass * b.pos.y)).abs() < 1e-12);
+            // and they end just touching
+            assert!((c.pos.separation(d.pos).modulus() - (a.radius + b.radius)).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn receding_overlap_only_separates() {
+        let _g = lock();
+        let mut a = Particle::new(PhysVec { x: 0., y: 0. }, PhysVec { x: -1., y: 0. }, 1.);
+        let mut b = Particle::new(PhysVec { x: 1., y: 0. }, PhysVec { x: 1., y: 0. }, 1.);
+        a.radius = 1.;
+        b.radius = 1.;
+        b.id = 1;
+        let mut particles = vec![a, b];
+        assert_eq!(Collisions::new().bounce(&mut particles, 1.), 0);
+        assert!(particles[0].vel == a.vel && particles[1].vel == b.vel);
+        assert!((particles[1].pos.x - particles[0].pos.x - 2.).abs() < 1e-12);
+    }
+
+    #[test]
+    fn tracers_neither_merge_nor_bounce() {
+        let _g = lock();
+        let mut star = Particle::new(PhysVec { x: 0., y: 0. }, PhysVec { x: 1., y: 0. }, 1.);
+        star.radius = 1.;
+        let mut tracer = Particle::new(PhysVec { x: 0.5, y: 0. }, PhysVec { x: -1., y: 0. }, 5.);
+        tracer.kind = Species::Test;
+        tracer.radius = 1.;
+        tracer.id = 1;
+        let mut particles = vec![star, tracer];
+        let mut collisions = Collisions::new();
+        assert!(collisions.merge(&mut particles, 1.).len() == 0 && particles.len() == 2);
+        assert!(collisions.bounce(&mut particles, 1.) == 0);
+        assert!(particles[0] == star && particles[1] == tracer);
+    }
+}
@@ -0,0 +1,53 @@
+use std::ops::{Add, Sub, Mul, Div};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Complex {
+    pub re: f64,
+    pub im: f64
+}
+
+impl Complex {
+    pub fn new(re: f64, im: f64) -> Complex {
+        Complex { re: re, im: im }
+    }
+
+    pub fn zero() -> Complex {
+        Complex { re: 0., im: 0. }
+    }
+
+    pub fn scale(&self, s: f64) -> Complex {
+        Complex { re: self.re * s, im: self.im * s }
+    }
+
+    pub fn norm_sqr(&self) -> f64 {
+        self.re * self.re + self.im * self.im
+    }
+
+    pub fn inv(&self) -> Complex {
+        let n = self.norm_sqr();
+        Complex { re: self.re / n, im: -self.im / n }
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+    fn add(self, o: Complex) -> Complex { Complex { re: self.re + o.re, im: self.im + o.im } }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+    fn sub(self, o: Complex) -> Complex { Complex { re: self.re - o.re, im: self.im - o.im } }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+    fn mul(self, o: Complex) -> Complex {
+        Complex { re: self.re * o.re - self.im * o.im, im: self.re * o.im + self.im * o.re }
+    }
+}
+
+impl Div for Complex {
+    type Output = Complex;
+    #[allow(clippy::suspicious_arithmetic_impl)]
+    fn div(self, o: Complex) -> Complex { self * o.inv() }
+}
@@ -1,41 +1,324 @@
-#[derive(RustcDecodable, Debug)]
+use rustc_serialize::{Decodable, Decoder};
+use pm::MARGIN;
+
+// The config is read with rustc-serialize's Decodable, implemented here rather than derived.
+// Structs list their fields once more, so the compiler catches one left out; a missing
+// Option field decodes to None, as the toml decoder has nothing to hand read_option.
+macro_rules! decode_struct {
+    ($name:ident { $($field:ident),* }) => {
+        impl Decodable for $name {
+            fn decode<D: Decoder>(d: &mut D) -> Result<$name, D::Error> {
+                d.read_struct(stringify!($name), 0, |d| {
+                    Ok($name { $($field: d.read_struct_field(stringify!($field), 0, Decodable::decode)?),* })
+                })
+            }
+        }
+    }
+}
+
+// Unit enums are read from their names in the config, and anything else is an error. (The
+// toml decoder tries an enum's variants in turn, taking the first that decodes, and a unit
+// variant always decodes, so a derived impl would give the first variant whatever was written)
+macro_rules! decode_names {
+    ($name:ident { $($text:expr => $variant:ident),* }) => {
+        impl Decodable for $name {
+            fn decode<D: Decoder>(d: &mut D) -> Result<$name, D::Error> {
+                let s = d.read_str()?;
+                match &s[..] {
+                    $($text => Ok($name::$variant),)*
+                    _ => Err(d.error(&format!("unknown {} \"{}\", expected one of: {}", stringify!($name), s,
+                                              [$($text),*].join(", "))))
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
 pub enum SimType {
     BarnesHut,
     BarnesHutParallel,
-    Classical
+    Classical,
+    ClassicalParallel,
+    Fmm,
+    ParticleMesh,
+    TreePm
+}
+
+decode_names!(SimType {
+    "barnes-hut"          => BarnesHut,
+    "barnes-hut-parallel" => BarnesHutParallel,
+    "classical"           => Classical,
+    "classical-parallel"  => ClassicalParallel,
+    "fmm"                 => Fmm,
+    "particle-mesh"       => ParticleMesh,
+    "tree-pm"             => TreePm
+});
+
+// How particle-mesh solvers spread each particle's mass over the mesh
+#[derive(Debug, Clone, Copy)]
+pub enum MassAssignment {
+    Ngp,                // nearest grid point
+    Cic,                // cloud in cell, 2x2 points
+    Tsc                 // triangular shaped cloud, 3x3 points
+}
+
+decode_names!(MassAssignment {
+    "ngp" => Ngp,
+    "cic" => Cic,
+    "tsc" => Tsc
+});
+
+// How particles are advanced each frame
+#[derive(Debug, Clone, Copy)]
+pub enum Timestepping {
+    Fixed,              // everyone steps by dt
+    Block,              // per-particle steps of dt/2^k, k from each particle's acceleration
+    Adaptive            // one step for everyone, from the largest acceleration, each frame
+}
+
+decode_names!(Timestepping {
+    "fixed"    => Fixed,
+    "block"    => Block,
+    "adaptive" => Adaptive
+});
+
+// What a particle stands for. Physics modules pick out the species they act on, and
+// each is drawn in its own colour
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Species {
+    Star,
+    Gas,
+    DarkMatter,
+    BlackHole,
+    Test
+}
+
+decode_names!(Species {
+    "star"        => Star,
+    "gas"         => Gas,
+    "dark-matter" => DarkMatter,
+    "black-hole"  => BlackHole,
+    "test"        => Test
+});
+
+// What happens to particles that leave the system
+#[derive(Debug, Clone, Copy)]
+pub enum EscapeAction {
+    Off,                // no checks
+    Remove,             // dropped from the run
+    Freeze              // set aside, still written to snapshots
+}
+
+decode_names!(EscapeAction {
+    "off"    => Off,
+    "remove" => Remove,
+    "freeze" => Freeze
+});
+
+#[derive(Debug, Clone, Copy)]
+pub enum Eos {
+    Adiabatic,          // P = (gamma - 1) rho u, with u evolved
+    Isothermal          // P = sound_speed^2 rho
 }
 
-#[derive(RustcDecodable, Debug)]
+decode_names!(Eos {
+    "adiabatic"  => Adiabatic,
+    "isothermal" => Isothermal
+});
+
+// Rule deciding when a tree node is far enough away to be treated as a single mass.
+// The meaning of `threshold` depends on the criterion chosen
+#[derive(Debug, Clone, Copy)]
+pub enum OpeningCriterion {
+    Geometric,          // accept if distance/width > threshold
+    BarnesHut,          // accept if width/distance < threshold (theta): Geometric with 1/theta
+    Bmax,               // Salmon-Warren: accept if distance > bmax/threshold
+    RelativeError       // GADGET: accept if estimated force error < threshold * |a_old|
+}
+
+decode_names!(OpeningCriterion {
+    "geometric"      => Geometric,
+    "barnes-hut"     => BarnesHut,
+    "bmax"           => Bmax,
+    "relative-error" => RelativeError
+});
+
+#[derive(Debug)]
 pub struct Config {
     pub display :  Display,
     pub galaxies:  Vec<GalaxyCfg>,
     pub sim:       SimType,
     pub threshold: f64,
-    pub dt       : f64
+    pub criterion: OpeningCriterion,
+    pub dt       : f64,
+    pub sort_every: u32,
+    pub threads  : Option<usize>,         // defaults to the number of cores
+    pub fmm_order: u32,
+    pub dimensions: u32,                 // 2, or 3 for the octree and 3D galaxies
+    pub pm_grid  : u32,
+    pub pm_assignment: MassAssignment,
+    pub pm_split : f64,
+    pub timestepping: Timestepping,
+    pub block_levels: u32,              // smallest block step is dt/2^block_levels
+    pub eta      : f64,
+    pub step_eps : f64,                 // length scale in the step criterion eta*sqrt(eps/|a|)
+    pub dt_min   : f64,                 // bounds on the adaptive step
+    pub dt_max   : f64,
+    pub merge_radius: f64,              // particles closer than this merge, 0 to disable
+    pub hard_spheres: bool,             // bounce particles off each other at their radii
+    pub restitution: f64,               // 1 for elastic bounces, less to lose energy
+    pub merge_log: Option<String>,      // file listing mergers, otherwise only counted
+    pub potentials: Option<Vec<PotentialCfg>>,
+    pub box_size : f64,                 // side of a periodic box around the origin, 0 for open space
+    pub cosmology: bool,                // comoving coordinates in an expanding background
+    pub omega_m  : f64,
+    pub omega_l  : f64,
+    pub hubble   : f64,                 // H0, in simulation units
+    pub a_start  : f64,                 // scale factor at t = 0
+    pub fields   : Option<Vec<FieldCfg>>,
+    pub eos      : Eos,                 // gas equation of state
+    pub gamma    : f64,                 // adiabatic index
+    pub sound_speed: f64,               // isothermal sound speed
+    pub sph_neighbours: u32,
+    pub sph_h    : f64,                 // starting smoothing length
+    pub visc_alpha: f64,
+    pub visc_beta: f64,
+    pub bh_friction: bool,              // black hole dynamical friction
+    pub bh_friction_radius: f64,        // region sampled for the background around a hole
+    pub bh_accretion_radius: f64,       // holes swallow bound particles within this, 0 to disable
+    pub bh_capture_radius: f64,         // holes closer than this merge, 0 to disable
+    pub escape   : EscapeAction,
+    pub escape_radius: f64,             // particles only escape from beyond this
+    pub escape_log: Option<String>      // file listing escapers, otherwise they are printed
 }
 
-#[derive(RustcDecodable, Debug)]
+decode_struct!(Config {
+    display, galaxies, sim, threshold, criterion, dt, sort_every, threads, fmm_order, dimensions,
+    pm_grid, pm_assignment, pm_split, timestepping, block_levels, eta, step_eps, dt_min, dt_max,
+    merge_radius, hard_spheres, restitution, merge_log, potentials, box_size, cosmology, omega_m,
+    omega_l, hubble, a_start, fields, eos, gamma, sound_speed, sph_neighbours, sph_h, visc_alpha,
+    visc_beta, bh_friction, bh_friction_radius, bh_accretion_radius, bh_capture_radius, escape,
+    escape_radius, escape_log
+});
+
+#[derive(Debug)]
 pub struct ConfigOpt {
     pub display :  Option<DisplayOpt>,
-    pub galaxies  :  Vec<GalaxyCfg>,
+    pub galaxies  :  Option<Vec<GalaxyCfg>>,
     pub sim:       Option<SimType>,
     pub threshold: Option<f64>,
-    pub dt       : Option<f64>
+    pub criterion: Option<OpeningCriterion>,
+    pub dt       : Option<f64>,
+    pub sort_every: Option<u32>,
+    pub threads  : Option<usize>,
+    pub fmm_order: Option<u32>,
+    pub dimensions: Option<u32>,
+    pub pm_grid  : Option<u32>,
+    pub pm_assignment: Option<MassAssignment>,
+    pub pm_split : Option<f64>,
+    pub timestepping: Option<Timestepping>,
+    pub block_levels: Option<u32>,
+    pub eta      : Option<f64>,
+    pub step_eps : Option<f64>,
+    pub dt_min   : Option<f64>,
+    pub dt_max   : Option<f64>,
+    pub merge_radius: Option<f64>,
+    pub hard_spheres: Option<bool>,
+    pub restitution: Option<f64>,
+    pub merge_log: Option<String>,
+    pub potentials: Option<Vec<PotentialCfg>>,
+    pub box_size : Option<f64>,
+    pub cosmology: Option<bool>,
+    pub omega_m  : Option<f64>,
+    pub omega_l  : Option<f64>,
+    pub hubble   : Option<f64>,
+    pub a_start  : Option<f64>,
+    pub fields   : Option<Vec<FieldCfg>>,
+    pub eos      : Option<Eos>,
+    pub gamma    : Option<f64>,
+    pub sound_speed: Option<f64>,
+    pub sph_neighbours: Option<u32>,
+    pub sph_h    : Option<f64>,
+    pub visc_alpha: Option<f64>,
+    pub visc_beta: Option<f64>,
+    pub bh_friction: Option<bool>,
+    pub bh_friction_radius: Option<f64>,
+    pub bh_accretion_radius: Option<f64>,
+    pub bh_capture_radius: Option<f64>,
+    pub escape   : Option<EscapeAction>,
+    pub escape_radius: Option<f64>,
+    pub escape_log: Option<String>
+}
+
+decode_struct!(ConfigOpt {
+    display, galaxies, sim, threshold, criterion, dt, sort_every, threads, fmm_order, dimensions,
+    pm_grid, pm_assignment, pm_split, timestepping, block_levels, eta, step_eps, dt_min, dt_max,
+    merge_radius, hard_spheres, restitution, merge_log, potentials, box_size, cosmology, omega_m,
+    omega_l, hubble, a_start, fields, eos, gamma, sound_speed, sph_neighbours, sph_h, visc_alpha,
+    visc_beta, bh_friction, bh_friction_radius, bh_accretion_radius, bh_capture_radius, escape,
+    escape_radius, escape_log
+});
+
+// take each field given in `opt`, leaving the rest as they were
+macro_rules! overwrite {
+    ($cfg:ident, $opt:ident, [$($field:ident),*], [$($optional:ident),*]) => {
+        $(match $opt.$field { Some(v) => $cfg.$field = v, None => () })*
+        $(if $opt.$optional.is_some() { $cfg.$optional = $opt.$optional })*
+    }
+}
+
+//the config file's settings over the defaults. Galaxies replace the default ones as a
+//whole, each taking whatever it leaves out from the first default galaxy
+pub fn merge(default: Config, opt: ConfigOpt) -> Config {
+    let mut cfg = default;
+    match opt.display {
+        Some(d) => {
+            match d.width  { Some(w) => cfg.display.width = w as i32, None => () }
+            match d.height { Some(h) => cfg.display.height = h as i32, None => () }
+            match d.tilt   { Some(t) => cfg.display.tilt = t, None => () }
+        },
+        None => ()
+    }
+    match opt.galaxies {
+        Some(gals) => {
+            let template = cfg.galaxies.first().cloned();
+            cfg.galaxies = gals.into_iter().map(|gal| match template {
+                Some(ref t) => gal.or(t),
+                None        => gal
+            }).collect();
+        },
+        None => ()
+    }
+    overwrite!(cfg, opt,
+               [sim, threshold, criterion, dt, sort_every, fmm_order, dimensions, pm_grid, pm_assignment,
+                pm_split, timestepping, block_levels, eta, step_eps, dt_min, dt_max, merge_radius,
+                hard_spheres, restitution, box_size, cosmology, omega_m, omega_l, hubble, a_start, eos,
+                gamma, sound_speed, sph_neighbours, sph_h, visc_alpha, visc_beta, bh_friction,
+                bh_friction_radius, bh_accretion_radius, bh_capture_radius, escape, escape_radius],
+               [threads, merge_log, potentials, fields, escape_log]);
+    cfg
 }
 
-#[derive(RustcDecodable, Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy)]
 pub struct Display {
     pub width: i32,
-    pub height: i32
+    pub height: i32,
+    pub tilt: f64               // degrees the 3D view is tipped about the screen's x axis
 }
 
-#[derive(RustcDecodable, Debug)]
+decode_struct!(Display { width, height, tilt });
+
+#[derive(Debug)]
 pub struct DisplayOpt {
     pub width: Option<u32>,
-    pub height: Option<u32>
+    pub height: Option<u32>,
+    pub tilt: Option<f64>
 }
 
-#[derive(RustcDecodable, Debug, Clone)]
+decode_struct!(DisplayOpt { width, height, tilt });
+
+#[derive(Debug, Clone)]
 pub struct GalaxyCfg {
     pub posx: Option<f64>,
     pub posy: Option<f64>,
@@ -46,21 +329,490 @@ pub struct GalaxyCfg {
     pub shape: Option<GalaxyShape>,
     pub kinetics: Option<GalaxyKinetics>,
     pub central_mass: Option<f64>,
-    pub other_mass: Option<f64>
+    #[allow(dead_code)]
+    pub other_mass: Option<f64>,           // read but unused: bodies have unit mass
+    pub posz: Option<f64>,                 // 3D only
+    pub velz: Option<f64>,
+    pub thickness: Option<f64>,            // scale height of a 3D disk
+    pub inclination: Option<f64>,          // degrees, about the x axis
+    pub central_radius: Option<f64>,       // hard-sphere sizes, 2D only
+    pub body_radius: Option<f64>,
+    pub species: Option<Species>,          // of the bodies, star unless given
+    pub central_species: Option<Species>,
+    pub tracers: Option<u32>,              // test particles laid out like the bodies, on top of them
+    pub gas_fraction: Option<f64>,         // share of the bodies made SPH gas, 2D only
+    pub gas_energy: Option<f64>            // initial specific internal energy of the galaxy's gas
+}
+
+decode_struct!(GalaxyCfg {
+    posx, posy, velx, vely, radius, nbody, shape, kinetics, central_mass, other_mass, posz, velz,
+    thickness, inclination, central_radius, body_radius, species, central_species, tracers,
+    gas_fraction, gas_energy
+});
+
+impl GalaxyCfg {
+    //this galaxy, with the fields it leaves out taken from other
+    fn or(self, other: &GalaxyCfg) -> GalaxyCfg {
+        GalaxyCfg {
+            posx: self.posx.or(other.posx),
+            posy: self.posy.or(other.posy),
+            velx: self.velx.or(other.velx),
+            vely: self.vely.or(other.vely),
+            radius: self.radius.or(other.radius),
+            nbody: self.nbody,
+            shape: self.shape.or(other.shape),
+            kinetics: self.kinetics.or(other.kinetics),
+            central_mass: self.central_mass.or(other.central_mass),
+            other_mass: self.other_mass.or(other.other_mass),
+            posz: self.posz.or(other.posz),
+            velz: self.velz.or(other.velz),
+            thickness: self.thickness.or(other.thickness),
+            inclination: self.inclination.or(other.inclination),
+            central_radius: self.central_radius.or(other.central_radius),
+            body_radius: self.body_radius.or(other.body_radius),
+            species: self.species.or(other.species),
+            central_species: self.central_species.or(other.central_species),
+            tracers: self.tracers.or(other.tracers),
+            gas_fraction: self.gas_fraction.or(other.gas_fraction),
+            gas_energy: self.gas_energy.or(other.gas_energy)
+        }
+    }
+}
+
+// A fixed analytic potential acting on every particle. Which parameters matter depends
+// on the kind: point-mass takes mass; logarithmic vcirc and scale (core radius);
+// nfw mass (4 pi rho0 rs^3) and scale (rs); miyamoto-nagai mass, scale (a) and scale_z (b)
+#[derive(Debug, Clone)]
+pub struct PotentialCfg {
+    pub kind: PotentialKind,
+    pub mass: Option<f64>,
+    pub vcirc: Option<f64>,
+    pub scale: Option<f64>,
+    pub scale_z: Option<f64>,
+    pub posx: Option<f64>,
+    pub posy: Option<f64>,
+    pub posz: Option<f64>,
+    pub galaxy: Option<usize>              // index into galaxies; the potential follows its central mass
 }
 
+decode_struct!(PotentialCfg {
+    kind, mass, vcirc, scale, scale_z, posx, posy, posz, galaxy
+});
+
+#[derive(Debug, Clone, Copy)]
+pub enum PotentialKind {
+    PointMass,
+    Logarithmic,
+    Nfw,
+    MiyamotoNagai
+}
+
+decode_names!(PotentialKind {
+    "point-mass"     => PointMass,
+    "logarithmic"    => Logarithmic,
+    "nfw"            => Nfw,
+    "miyamoto-nagai" => MiyamotoNagai
+});
+
+// A lattice of particles perturbed by a Gaussian random field (Zel'dovich approximation),
+// an alternative to a galaxy for cosmological starts. 2D only
+#[derive(Debug, Clone)]
+pub struct FieldCfg {
+    pub grid: u32,                         // particles per side, a power of two
+    pub size: f64,                         // side of the square the lattice fills
+    pub spectral_index: f64,               // P(k) ~ k^spectral_index
+    pub sigma: f64,                        // rms density contrast of the field on the grid
+    pub velocity: Option<f64>,             // velocity per unit displacement, f*H for the growing mode
+    pub mass: Option<f64>,
+    pub posx: Option<f64>,
+    pub posy: Option<f64>,
+    pub velx: Option<f64>,
+    pub vely: Option<f64>
+}
+
+decode_struct!(FieldCfg {
+    grid, size, spectral_index, sigma, velocity, mass, posx, posy, velx, vely
+});
+
 //Represents internal shape of galaxy
-#[derive(RustcDecodable, Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy)]
 pub enum GalaxyShape {
     RandomWeighted,
     RandomEven,
-    Concentric(u32)
+    Concentric(u32),
+    Spheroid(f64)              // 3D only, argument is the axis ratio z/x
 }
 
-#[derive(RustcDecodable, Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy)]
 pub enum GalaxyKinetics {
     RandomVel(f64, f64),
     CircularOrbit,
     ZeroVel,
 }
 
+// Variants without arguments are written as their names, those with them as a one key
+// table, e.g. shape = { concentric = 20 } or kinetics = { random-vel = [0.0, 10.0] }.
+// (A mismatched read_str leaves the value in place, so the table can be read after it)
+impl Decodable for GalaxyShape {
+    fn decode<D: Decoder>(d: &mut D) -> Result<GalaxyShape, D::Error> {
+        match d.read_str() {
+            Ok(s) => return match &s[..] {
+                "random-weighted" => Ok(GalaxyShape::RandomWeighted),
+                "random-even"     => Ok(GalaxyShape::RandomEven),
+                _ => Err(d.error(&format!("unknown GalaxyShape \"{}\", expected one of: random-weighted, \
+                                           random-even, {{ concentric = rings }}, {{ spheroid = ratio }}", s)))
+            },
+            Err(_) => ()
+        }
+        d.read_struct("GalaxyShape", 0, |d| {
+            let rings: Option<u32> = d.read_struct_field("concentric", 0, Decodable::decode)?;
+            let ratio: Option<f64> = d.read_struct_field("spheroid", 0, Decodable::decode)?;
+            match (rings, ratio) {
+                (Some(n), None) => Ok(GalaxyShape::Concentric(n)),
+                (None, Some(q)) => Ok(GalaxyShape::Spheroid(q)),
+                _ => Err(d.error("a galaxy shape table needs exactly one of concentric or spheroid"))
+            }
+        })
+    }
+}
+
+impl Decodable for GalaxyKinetics {
+    fn decode<D: Decoder>(d: &mut D) -> Result<GalaxyKinetics, D::Error> {
+        match d.read_str() {
+            Ok(s) => return match &s[..] {
+                "circular-orbit" => Ok(GalaxyKinetics::CircularOrbit),
+                "zero-vel"       => Ok(GalaxyKinetics::ZeroVel),
+                _ => Err(d.error(&format!("unknown GalaxyKinetics \"{}\", expected one of: circular-orbit, \
+                                           zero-vel, {{ random-vel = [min, max] }}", s)))
+            },
+            Err(_) => ()
+        }
+        d.read_struct("GalaxyKinetics", 0, |d| {
+            let range: Option<Vec<f64>> = d.read_struct_field("random_vel", 0, Decodable::decode)?;
+            match range {
+                Some(ref v) if v.len() == 2 => Ok(GalaxyKinetics::RandomVel(v[0], v[1])),
+                _ => Err(d.error("a galaxy kinetics table needs random-vel = [min, max]"))
+            }
+        })
+    }
+}
+
+
+//galaxies with any SPH gas in them
+fn has_gas(gal: &GalaxyCfg) -> bool {
+    gal.species == Some(Species::Gas) || gal.central_species == Some(Species::Gas) || gal.gas_fraction.unwrap_or(0.) > 0.
+}
+
+// each frame of a block stepped run is split into 2^block_levels substeps
+pub static MAX_BLOCK_LEVELS: u32 = 16;
+
+//the parameters each kind of potential can't do without
+fn check_potential(ix: usize, pot: &PotentialCfg, ngalaxies: usize) -> Result<(), String> {
+    let needs = match pot.kind {
+        PotentialKind::PointMass     => vec![("mass", pot.mass)],
+        PotentialKind::Logarithmic   => vec![("vcirc", pot.vcirc), ("scale", pot.scale)],
+        PotentialKind::Nfw           => vec![("mass", pot.mass), ("scale", pot.scale)],
+        PotentialKind::MiyamotoNagai => vec![("mass", pot.mass), ("scale", pot.scale), ("scale_z", pot.scale_z)]
+    };
+    for &(name, value) in needs.iter() {
+        match value {
+            None => return Err(format!("potential {} ({:?}) needs {}", ix, pot.kind, name)),
+            Some(v) if v < 0. => return Err(format!("potential {} has a negative {}", ix, name)),
+            _ => ()
+        }
+    }
+    match (pot.kind, pot.scale) {
+        (PotentialKind::Nfw, Some(0.)) => return Err(format!("potential {} (Nfw) needs a scale above 0", ix)),
+        _ => ()
+    }
+    match pot.galaxy {
+        Some(g) if g >= ngalaxies => Err(format!("potential {} follows galaxy {}, but there are only {}", ix, g, ngalaxies)),
+        _ => Ok(())
+    }
+}
+
+//catch settings that can't work together before anything is set up
+pub fn validate(cfg: &Config) -> Result<(), String> {
+    if cfg.dimensions != 2 && cfg.dimensions != 3 {
+        return Err(format!("dimensions must be 2 or 3, not {}", cfg.dimensions))
+    }
+    match cfg.potentials {
+        Some(ref pots) => for (ix, pot) in pots.iter().enumerate() {
+            check_potential(ix, pot, cfg.galaxies.len())?;
+        },
+        None => ()
+    }
+    // a potential is pinned to one image of its centre, which a periodic box has no way to honour
+    if cfg.box_size > 0. && cfg.potentials.as_ref().map_or(false, |pots| pots.len() > 0) {
+        return Err("external potentials can't be used in a periodic box".to_string())
+    }
+    match cfg.fields {
+        Some(ref fields) => for (ix, field) in fields.iter().enumerate() {
+            // the FFTs that shape the field are radix 2
+            if !field.grid.is_power_of_two() {
+                return Err(format!("field {} has grid = {}, which must be a power of two", ix, field.grid))
+            }
+            if field.size <= 0. {
+                return Err(format!("field {} needs a size above 0", ix))
+            }
+        },
+        None => ()
+    }
+    match cfg.timestepping {
+        Timestepping::Fixed => (),
+        Timestepping::Block | Timestepping::Adaptive => {
+            if cfg.eta <= 0. || cfg.step_eps <= 0. {
+                return Err("eta and step_eps must be above 0".to_string())
+            }
+            if cfg.dt_min <= 0. || cfg.dt_min > cfg.dt_max {
+                return Err(format!("need 0 < dt_min <= dt_max, not dt_min = {} and dt_max = {}", cfg.dt_min, cfg.dt_max))
+            }
+        }
+    }
+    if cfg.dimensions == 3 {
+        return validate3d(cfg)
+    }
+    match cfg.sim {
+        // the particles span the mesh less MARGIN cells on either side, and one more
+        SimType::ParticleMesh | SimType::TreePm => {
+            let n = cfg.pm_grid as usize;
+            if !n.is_power_of_two() || n <= 2 * MARGIN + 1 {
+                return Err(format!("pm_grid must be a power of two above {}, not {}", 2 * MARGIN + 1, n))
+            }
+        },
+        _ => ()
+    }
+    if cfg.box_size > 0. {
+        match cfg.sim {
+            SimType::BarnesHut | SimType::BarnesHutParallel | SimType::Classical => (),
+            _ => return Err("periodic boxes need the classical, barnes-hut or barnes-hut-parallel solver".to_string())
+        }
+        match cfg.escape {
+            EscapeAction::Off => (),
+            _ => return Err("nothing escapes a periodic box; set escape = \"off\"".to_string())
+        }
+    }
+    for (ix, gal) in cfg.galaxies.iter().enumerate() {
+        match gal.shape {
+            Some(GalaxyShape::Spheroid(_)) => return Err(format!("galaxy {} is a spheroid, which needs dimensions = 3", ix)),
+            _ => ()
+        }
+    }
+    match cfg.timestepping {
+        Timestepping::Block => {
+            if cfg.cosmology {
+                return Err("block timesteps can't be used in comoving runs".to_string())
+            }
+            if cfg.block_levels > MAX_BLOCK_LEVELS {
+                return Err(format!("block_levels can be at most {}", MAX_BLOCK_LEVELS))
+            }
+            // the other solvers find every force whether it is wanted or not, which at
+            // 2^block_levels force passes a frame is far slower than fixed steps
+            match cfg.sim {
+                SimType::BarnesHut | SimType::BarnesHutParallel => (),
+                _ => return Err("block timesteps need the barnes-hut or barnes-hut-parallel solver".to_string())
+            }
+        },
+        _ => ()
+    }
+    Ok(())
+}
+
+//the reversibility check runs fixed steps of the bare solver, so anything else would be ignored
+pub fn validate_reverse(cfg: &Config) -> Result<(), String> {
+    if cfg.dimensions == 3 {
+        return Err("reversibility checks are only available in 2D".to_string())
+    }
+    match cfg.timestepping {
+        Timestepping::Fixed => (),
+        _ => return Err("reversibility checks need fixed timesteps".to_string())
+    }
+    if cfg.cosmology {
+        return Err("reversibility checks can't be made in comoving runs".to_string())
+    }
+    if cfg.galaxies.iter().any(has_gas) {
+        return Err("reversibility checks don't run SPH gas".to_string())
+    }
+    if cfg.merge_radius > 0. || cfg.hard_spheres {
+        return Err("reversibility checks don't merge or bounce particles".to_string())
+    }
+    if cfg.bh_friction || cfg.bh_accretion_radius > 0. || cfg.bh_capture_radius > 0. {
+        return Err("reversibility checks don't run black hole friction, accretion or capture".to_string())
+    }
+    match cfg.escape {
+        EscapeAction::Off => Ok(()),
+        _ => Err("reversibility checks don't remove escapers; set escape = \"off\"".to_string())
+    }
+}
+
+fn validate3d(cfg: &Config) -> Result<(), String> {
+    match cfg.sim {
+        SimType::BarnesHut | SimType::BarnesHutParallel | SimType::Classical => (),
+        _ => return Err(format!("{:?} is only available in 2D; 3D runs support classical, barnes-hut and \
+                                 barnes-hut-parallel", cfg.sim))
+    }
+    match cfg.timestepping {
+        Timestepping::Block => return Err("block timesteps are only available in 2D".to_string()),
+        _ => ()
+    }
+    if cfg.box_size > 0. {
+        return Err("periodic boxes are only available in 2D".to_string())
+    }
+    if cfg.fields.is_some() {
+        return Err("random field initial conditions are only available in 2D".to_string())
+    }
+    if cfg.merge_radius > 0. || cfg.hard_spheres {
+        return Err("merging and hard spheres are only available in 2D".to_string())
+    }
+    if cfg.galaxies.iter().any(has_gas) {
+        return Err("SPH gas is only available in 2D".to_string())
+    }
+    if cfg.bh_friction || cfg.bh_accretion_radius > 0. || cfg.bh_capture_radius > 0. {
+        return Err("black hole friction, accretion and capture are only available in 2D".to_string())
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Read;
+    use toml;
+    use std::fmt::Debug;
+    use rustc_serialize::Decodable;
+    use super::{validate, merge, Config, ConfigOpt, GalaxyShape, GalaxyKinetics, SimType, MassAssignment,
+                Timestepping, Species, EscapeAction, Eos, OpeningCriterion, PotentialKind};
+
+    pub fn default_config() -> Config {
+        let mut text = String::new();
+        File::open("config/default.toml").unwrap().read_to_string(&mut text).unwrap();
+        toml::decode(toml::Value::Table(toml::Parser::new(&text).parse().unwrap())).unwrap()
+    }
+
+    fn decode<T: ::rustc_serialize::Decodable>(text: &str) -> Result<T, toml::DecodeError> {
+        let table = toml::Parser::new(text).parse().unwrap();
+        ::rustc_serialize::Decodable::decode(&mut toml::Decoder::new(toml::Value::Table(table)))
+    }
+
+    //the value written as `v = <text>`, decoded and printed back through Debug
+    fn value<T: Decodable + Debug>(text: &str) -> Result<String, toml::DecodeError> {
+        let mut table = toml::Parser::new(&format!("v = {}", text)).parse().unwrap();
+        let v: T = Decodable::decode(&mut toml::Decoder::new(table.remove("v").unwrap()))?;
+        Ok(format!("{:?}", v))
+    }
+
+    fn check_names<T: Decodable + Debug>(names: &[(&str, &str)]) {
+        for &(text, variant) in names {
+            assert_eq!(value::<T>(&format!("\"{}\"", text)).unwrap(), variant);
+        }
+        let err = value::<T>("\"no-such-thing\"").unwrap_err().to_string();
+        assert!(err.contains("no-such-thing") && err.contains(names[0].0), "{}", err);
+    }
+
+    #[test]
+    fn enums_decode_by_name() {
+        check_names::<SimType>(&[("barnes-hut", "BarnesHut"), ("barnes-hut-parallel", "BarnesHutParallel"),
+                                 ("classical", "Classical"), ("classical-parallel", "ClassicalParallel"),
+                                 ("fmm", "Fmm"), ("particle-mesh", "ParticleMesh"), ("tree-pm", "TreePm")]);
+        check_names::<MassAssignment>(&[("ngp", "Ngp"), ("cic", "Cic"), ("tsc", "Tsc")]);
+        check_names::<Timestepping>(&[("fixed", "Fixed"), ("block", "Block"), ("adaptive", "Adaptive")]);
+        check_names::<Species>(&[("star", "Star"), ("gas", "Gas"), ("dark-matter", "DarkMatter"),
+                                 ("black-hole", "BlackHole"), ("test", "Test")]);
+        check_names::<EscapeAction>(&[("off", "Off"), ("remove", "Remove"), ("freeze", "Freeze")]);
+        check_names::<Eos>(&[("adiabatic", "Adiabatic"), ("isothermal", "Isothermal")]);
+        check_names::<OpeningCriterion>(&[("geometric", "Geometric"), ("barnes-hut", "BarnesHut"),
+                                          ("bmax", "Bmax"), ("relative-error", "RelativeError")]);
+        check_names::<PotentialKind>(&[("point-mass", "PointMass"), ("logarithmic", "Logarithmic"),
+                                       ("nfw", "Nfw"), ("miyamoto-nagai", "MiyamotoNagai")]);
+        // not strings at all
+        assert!(value::<SimType>("3").is_err());
+    }
+
+    #[test]
+    fn galaxy_variants_decode() {
+        assert_eq!(value::<GalaxyShape>("\"random-weighted\"").unwrap(), "RandomWeighted");
+        assert_eq!(value::<GalaxyShape>("\"random-even\"").unwrap(), "RandomEven");
+        assert_eq!(value::<GalaxyShape>("{ concentric = 5 }").unwrap(), "Concentric(5)");
+        assert_eq!(value::<GalaxyShape>("{ spheroid = 0.5 }").unwrap(), "Spheroid(0.5)");
+        assert!(value::<GalaxyShape>("\"concentric\"").is_err());
+        assert!(value::<GalaxyShape>("{ concentric = 5, spheroid = 0.5 }").is_err());
+        assert!(value::<GalaxyShape>("{}").is_err());
+        assert_eq!(value::<GalaxyKinetics>("\"circular-orbit\"").unwrap(), "CircularOrbit");
+        assert_eq!(value::<GalaxyKinetics>("\"zero-vel\"").unwrap(), "ZeroVel");
+        assert_eq!(value::<GalaxyKinetics>("{ random-vel = [1.0, 2.0] }").unwrap(), "RandomVel(1.0, 2.0)");
+        assert!(value::<GalaxyKinetics>("{ random-vel = [1.0] }").is_err());
+        assert!(value::<GalaxyKinetics>("\"random\"").is_err());
+    }
+
+    #[test]
+    fn merge_over_defaults() {
+        let opt: ConfigOpt = decode("dt = 0.01\nthreads = 3\n[display]\nwidth = 640\n\
+                                     [[galaxies]]\nnbody = 10\nshape = { concentric = 2 }\n").unwrap();
+        let default = default_config();
+        let cfg = merge(default_config(), opt);
+        assert_eq!(cfg.dt, 0.01);
+        assert_eq!(cfg.threads, Some(3));
+        assert_eq!(cfg.display.width, 640);
+        assert_eq!(cfg.display.height, default.display.height);
+        assert_eq!(cfg.threshold, default.threshold);
+        assert_eq!(cfg.galaxies.len(), 1);
+        assert_eq!(cfg.galaxies[0].nbody, 10);
+        match cfg.galaxies[0].shape { Some(GalaxyShape::Concentric(2)) => (), s => panic!("shape {:?}", s) }
+        assert_eq!(cfg.galaxies[0].radius, default.galaxies[0].radius);
+        // nothing given, nothing changed
+        let cfg = merge(default_config(), decode("").unwrap());
+        assert_eq!(cfg.galaxies.len(), default.galaxies.len());
+        assert_eq!(cfg.dt, default.dt);
+    }
+
+    #[test]
+    fn step_bounds_checked() {
+        let mut cfg = default_config();
+        cfg.timestepping = Timestepping::Adaptive;
+        assert_eq!(validate(&cfg), Ok(()));
+        cfg.dt_min = 2. * cfg.dt_max;
+        assert!(validate(&cfg).is_err());
+        cfg.dt_min = cfg.dt_max;
+        assert_eq!(validate(&cfg), Ok(()));
+        cfg.eta = 0.;
+        assert!(validate(&cfg).is_err());
+        cfg.eta = 0.2;
+        cfg.step_eps = -1.;
+        assert!(validate(&cfg).is_err());
+    }
+
+    #[test]
+    fn potentials_need_open_space() {
+        let mut cfg = default_config();
+        cfg.potentials = decode::<ConfigOpt>("[[potentials]]\nkind = \"point-mass\"\nmass = 10.0\n").unwrap().potentials;
+        assert_eq!(validate(&cfg), Ok(()));
+        cfg.box_size = 1000.;
+        assert!(validate(&cfg).is_err());
+    }
+
+    #[test]
+    fn field_grid_checked() {
+        let mut cfg = default_config();
+        cfg.fields = decode::<ConfigOpt>("[[fields]]\ngrid = 16\nsize = 100.0\nspectral_index = -1.0\n\
+                                          sigma = 0.1\n").unwrap().fields;
+        assert_eq!(validate(&cfg), Ok(()));
+        for &n in &[0, 12] {
+            cfg.fields.as_mut().unwrap()[0].grid = n;
+            assert!(validate(&cfg).is_err(), "grid {} accepted", n);
+        }
+    }
+
+    #[test]
+    fn pm_grid_checked() {
+        let mut cfg = default_config();
+        cfg.sim = SimType::ParticleMesh;
+        for &n in &[0, 4, 100] {
+            cfg.pm_grid = n;
+            assert!(validate(&cfg).is_err(), "pm_grid {} accepted", n);
+        }
+        for &n in &[8, 256] {
+            cfg.pm_grid = n;
+            assert_eq!(validate(&cfg), Ok(()));
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use physics::{Particle, PhysVec, ForceSolver, DT, BOX_SIZE};
+use physics3d::{Particle3, Vec3, ForceSolver3};
+
+// Expanding-universe runs. Positions and velocities are comoving, x = r/a, with the scale
+// factor a(t) following a Friedmann model with matter, a cosmological constant and
+// whatever curvature makes up the difference. The solvers work on comoving positions,
+// so their forces are rescaled to the physical separations, and velocities feel a Hubble
+// drag: dv/dt = g/a^dims - (a''/a)x - 2Hv, where dims is 2 or 3 for the 1/r and 1/r^2
+// force laws. The middle term is the frame's acceleration about the origin, so isolated
+// systems move as they would in physical coordinates. A periodic box is a patch of the
+// background instead: the nearest image sum over a uniform box is zero, so its forces come
+// from the density less its mean, whose pull a(t) already follows, and the term is dropped.
+
+pub struct Cosmology {
+    omega_m: f64,
+    omega_l: f64,
+    h0: f64,
+    pub a: f64
+}
+
+impl Cosmology {
+    pub fn new(omega_m: f64, omega_l: f64, h0: f64, a_start: f64) -> Cosmology {
+        Cosmology { omega_m: omega_m, omega_l: omega_l, h0: h0, a: a_start }
+    }
+
+    //Hubble rate at scale factor a
+    pub fn hubble(&self, a: f64) -> f64 {
+        let omega_k = 1. - self.omega_m - self.omega_l;
+        self.h0 * (self.omega_m / (a * a * a) + omega_k / (a * a) + self.omega_l).sqrt()
+    }
+
+    //a''/a at scale factor a; curvature only changes the rate, not the acceleration
+    pub fn deceleration(&self, a: f64) -> f64 {
+        self.h0 * self.h0 * (self.omega_l - 0.5 * self.omega_m / (a * a * a))
+    }
+
+    //advance a by dt, integrating da/dt = aH(a) with RK4
+    fn advance(&mut self, dt: f64) {
+        let f = |a: f64| a * self.hubble(a);
+        let a = self.a;
+        let k1 = f(a);
+        let k2 = f(a + 0.5 * dt * k1);
+        let k3 = f(a + 0.5 * dt * k2);
+        let k4 = f(a + dt * k3);
+        self.a = a + dt / 6. * (k1 + 2. * k2 + 2. * k3 + k4);
+    }
+
+    //kick factors for a step of dt: drag on the old velocity, scale for comoving forces,
+    //and the background term's pull per unit distance from the origin
+    fn factors(&self, dims: i32, dt: f64) -> (f64, f64, f64) {
+        let pull = if unsafe { BOX_SIZE } > 0. { 0. } else { self.deceleration(self.a) };
+        ((-2. * self.hubble(self.a) * dt).exp(), self.a.powi(-dims), pull)
+    }
+}
+
+pub fn stepsim(particles: &mut Vec<Particle>, solver: &mut dyn ForceSolver, cosmo: &mut Cosmology,
+               frcs: &mut Vec<PhysVec>) {
+    frcs.resize(particles.len(), PhysVec {x: 0., y: 0.});
+    solver.forces(particles, frcs);
+    let dt = unsafe { DT };
+    let (drag, scale, pull) = cosmo.factors(2, dt);
+    for (p, &f) in particles.iter_mut().zip(frcs.iter()) {
+        p.acc = PhysVec { x: f.x/p.mass*scale, y: f.y/p.mass*scale };
+        p.vel.x = p.vel.x * drag + (p.acc.x - pull * p.pos.x) * dt;
+        p.vel.y = p.vel.y * drag + (p.acc.y - pull * p.pos.y) * dt;
+        p.steppos();
+    }
+    cosmo.advance(dt);
+}
+
+pub fn stepsim3(particles: &mut Vec<Particle3>, solver: &mut dyn ForceSolver3, cosmo: &mut Cosmology,
+                frcs: &mut Vec<Vec3>) {
+    frcs.resize(particles.len(), Vec3::zero());
+    solver.forces(particles, frcs);
+    let dt = unsafe { DT };
+    let (drag, scale, pull) = cosmo.factors(3, dt);
+    for (p, f) in particles.iter_mut().zip(frcs.iter()) {
+        p.acc = f.scale(scale / p.mass);
+        p.vel = p.vel.scale(drag);
+        p.vel.add(&p.acc.scale(dt));
+        p.vel.sub(&p.pos.scale(pull * dt));
+        p.steppos();
+    }
+    cosmo.advance(dt);
+}
+
+#[cfg(test)]
+mod tests {
+    use physics::{Particle, PhysVec, Classical, DT, BOX_SIZE};
+    use physics::tests::lock;
+    use super::{Cosmology, stepsim};
+
+    #[test]
+    fn einstein_de_sitter_expansion() {
+        // a^(3/2) grows as 3/2 H0 t when matter is all there is
+        let (h0, a0, dt) = (0.1, 0.02, 0.01);
+        let mut cosmo = Cosmology::new(1., 0., h0, a0);
+        for k in 1..20001 {
+            cosmo.advance(dt);
+            if k % 5000 == 0 {
+                let t = k as f64 * dt;
+                let exact = (a0.powf(1.5) + 1.5 * h0 * t).powf(2. / 3.);
+                assert!((cosmo.a - exact).abs() < 1e-6 * exact, "a = {} against {} at t = {}", cosmo.a, exact, t);
+            }
+        }
+        // and decelerates as a''/a = -H^2/2
+        assert!((cosmo.deceleration(cosmo.a) + 0.5 * cosmo.hubble(cosmo.a).powi(2)).abs() < 1e-15);
+    }
+
+    #[test]
+    fn uniform_lattice_stays_put() {
+        let _g = lock();
+        // an odd lattice filling the box, so no pair sits half a box apart
+        let (n, side) = (9, 90.);
+        unsafe { BOX_SIZE = side };
+        let cell = side / n as f64;
+        let mut particles: Vec<Particle> = (0..n * n).map(|k| {
+            let (i, j) = ((k % n) as f64, (k / n) as f64);
+            let pos = PhysVec { x: (i + 0.5) * cell - side / 2., y: (j + 0.5) * cell - side / 2. };
+            Particle::new(pos, PhysVec { x: 0., y: 0. }, 1.)
+        }).collect();
+        let start = particles.clone();
+        let mut cosmo = Cosmology::new(0.3, 0.7, 0.1, 0.02);
+        let mut frcs = Vec::new();
+        for _ in 0..200 {
+            stepsim(&mut particles, &mut Classical, &mut cosmo, &mut frcs);
+        }
+        assert!(cosmo.a > 0.03);
+        for (p, q) in particles.iter().zip(start.iter()) {
+            let moved = p.pos.separation(q.pos).modulus();
+            assert!(moved < 1e-9 * cell, "moved by {}", moved);
+        }
+    }
+
+    #[test]
+    fn isolated_body_moves_physically() {
+        let _g = lock();
+        // nothing pulls a lone body, so its physical position a x goes in a straight line
+        let dt = 1e-3;
+        unsafe { DT = dt };
+        let mut cosmo = Cosmology::new(0.3, 0.7, 0.1, 0.5);
+        let (x0, v0) = (PhysVec { x: 10., y: -4. }, PhysVec { x: 0.3, y: 0.5 });
+        let (a0, h) = (cosmo.a, cosmo.hubble(cosmo.a));
+        // physical velocity: peculiar plus Hubble flow
+        let u = PhysVec { x: a0 * (v0.x + h * x0.x), y: a0 * (v0.y + h * x0.y) };
+        let mut particles = vec![Particle::new(x0, v0, 1.)];
+        let mut frcs = Vec::new();
+        let steps = 20000;
+        for _ in 0..steps {
+            stepsim(&mut particles, &mut Classical, &mut cosmo, &mut frcs);
+        }
+        let t = steps as f64 * dt;
+        let r = PhysVec { x: cosmo.a * particles[0].pos.x, y: cosmo.a * particles[0].pos.y };
+        let expect = PhysVec { x: a0 * x0.x + u.x * t, y: a0 * x0.y + u.y * t };
+        assert!(cosmo.a > 0.8);
+        assert!(r.separation(expect).modulus() < 1e-2 * expect.modulus(), "at ({}, {}), not ({}, {})",
+                r.x, r.y, expect.x, expect.y);
+    }
+}
@@ -0,0 +1,121 @@
+use physics::{Particle, PhysVec, ForceSolver};
+use pool::ThreadPool;
+use std::cmp;
+use std::rc::Rc;
+
+// Exact O(N^2) forces spread over the thread pool. Positions and masses are copied into
+// separate arrays each step so the inner loop runs over contiguous f64s in fixed-width
+// groups the compiler can vectorise, and sources are visited in tiles small enough to
+// stay in cache while a whole chunk of targets is summed against them.
+
+static TILE: usize = 512;
+const LANES: usize = 4;
+
+pub struct ClassicalParallel {
+    xs: Vec<f64>,
+    ys: Vec<f64>,
+    ms: Vec<f64>,
+    pool: Rc<ThreadPool>
+}
+
+impl ClassicalParallel {
+    pub fn new(pool: Rc<ThreadPool>) -> ClassicalParallel {
+        ClassicalParallel { xs: Vec::new(), ys: Vec::new(), ms: Vec::new(), pool: pool }
+    }
+}
+
+impl ForceSolver for ClassicalParallel {
+    fn forces(&mut self, particles: &Vec<Particle>, frcs: &mut Vec<PhysVec>) {
+        self.xs.clear();
+        self.ys.clear();
+        self.ms.clear();
+        for p in particles {
+            self.xs.push(p.pos.x);
+            self.ys.push(p.pos.y);
+            self.ms.push(p.source_mass());
+        }
+        let (xs, ys, ms) = (&self.xs, &self.ys, &self.ms);
+        let n = particles.len();
+        self.pool.run_mut(frcs, self.pool.chunk_for(n), &|start, out: &mut [PhysVec]| {
+            for f in out.iter_mut() {
+                *f = PhysVec { x: 0., y: 0. };
+            }
+            let mut lo = 0;
+            while lo < n {
+                let hi = cmp::min(lo + TILE, n);
+                for (f, i) in out.iter_mut().zip(start..) {
+                    let (fx, fy) = tile_sum(xs[i], ys[i], &xs[lo..hi], &ys[lo..hi], &ms[lo..hi]);
+                    f.x += fx * particles[i].mass;
+                    f.y += fy * particles[i].mass;
+                }
+                lo = hi;
+            }
+        });
+    }
+}
+
+//sum of m_j * d_j / |d_j|^2 over the sources, d_j pointing from (x, y) to source j.
+//A source sitting exactly on the target (the target itself) contributes nothing.
+#[inline]
+fn tile_sum(x: f64, y: f64, xs: &[f64], ys: &[f64], ms: &[f64]) -> (f64, f64) {
+    let mut ax = [0.; LANES];
+    let mut ay = [0.; LANES];
+    let n = xs.len();
+    let body = n - n % LANES;
+    let mut j = 0;
+    while j < body {
+        for l in 0..LANES {
+            let dx = xs[j+l] - x;
+            let dy = ys[j+l] - y;
+            let r2 = dx*dx + dy*dy;
+            let w = if r2 > 0. { ms[j+l]/r2 } else { 0. };
+            ax[l] += w*dx;
+            ay[l] += w*dy;
+        }
+        j += LANES;
+    }
+    for k in body..n {
+        let dx = xs[k] - x;
+        let dy = ys[k] - y;
+        let r2 = dx*dx + dy*dy;
+        let w = if r2 > 0. { ms[k]/r2 } else { 0. };
+        ax[0] += w*dx;
+        ay[0] += w*dy;
+    }
+    (ax[0] + ax[1] + ax[2] + ax[3], ay[0] + ay[1] + ay[2] + ay[3])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use physics::tests::{lock, random_particles, exact_forces, forces_of, rel_errors};
+    use config::Species;
+    use pool::ThreadPool;
+    use super::ClassicalParallel;
+
+    #[test]
+    fn matches_classical() {
+        let _g = lock();
+        // enough particles for several tiles, and a few tracers
+        let mut particles = random_particles(1500, 100.);
+        for p in particles.iter_mut().take(20) { p.kind = Species::Test }
+        let mut solver = ClassicalParallel::new(Rc::new(ThreadPool::new(4)));
+        let (_, max) = rel_errors(&forces_of(&mut solver, &particles), &exact_forces(&particles));
+        assert!(max < 1e-10, "max error {}", max);
+    }
+
+    #[test]
+    fn coincident_pairs_skipped() {
+        let _g = lock();
+        let mut particles = random_particles(10, 100.);
+        let twin = particles[3];
+        particles.push(twin);
+        let tiled = forces_of(&mut ClassicalParallel::new(Rc::new(ThreadPool::new(2))), &particles);
+        let exact = exact_forces(&particles);
+        for (a, b) in tiled.iter().zip(exact.iter()) {
+            assert!(a.x.is_finite() && a.y.is_finite() && b.x.is_finite() && b.y.is_finite());
+        }
+        let (_, max) = rel_errors(&tiled, &exact);
+        assert!(max < 1e-10, "max error {}", max);
+    }
+}
@@ -0,0 +1,192 @@
+use config::{EscapeAction, Species};
+use physics::{Particle, PhysVec};
+use physics3d::{Particle3, Vec3};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+// Detection of particles leaving the system. A particle escapes once it is further than
+// the escape radius from the centre of mass, moving outwards, and has enough energy
+// relative to the system's total mass (taken as a point at the centre of mass) to get
+// away. In 3D that is the usual escape speed; the 2D logarithmic potential binds
+// everything, so there "away" means out to HORIZON escape radii. Escapers are either
+// dropped or frozen: kept aside, unmoving and out of the force calculation. Frozen
+// particles are not drawn, and only rejoin the others in the final snapshot.
+
+static HORIZON : f64 = 10.;
+
+pub struct Escapes {
+    radius: f64,
+    action: EscapeAction,
+    log: Option<BufWriter<File>>,
+    pub frozen: Vec<Particle>
+}
+
+pub struct Escapes3 {
+    radius: f64,
+    action: EscapeAction,
+    log: Option<BufWriter<File>>,
+    pub frozen: Vec<Particle3>
+}
+
+fn open_log(path: &str, columns: &str) -> BufWriter<File> {
+    let mut out = BufWriter::new(File::create(Path::new(path)).unwrap());
+    writeln!(out, "# time id species origin {}", columns).unwrap();
+    out
+}
+
+impl Escapes {
+    pub fn new(radius: f64, action: EscapeAction, log: Option<&str>) -> Escapes {
+        let log = log.map(|path| open_log(path, "posx posy velx vely mass"));
+        Escapes { radius: radius, action: action, log: log, frozen: Vec::new() }
+    }
+
+    //take out the particles that have escaped, returning how many there were
+    pub fn check(&mut self, particles: &mut Vec<Particle>, time: f64) -> usize {
+        match self.action {
+            EscapeAction::Off => return 0,
+            EscapeAction::Remove | EscapeAction::Freeze => ()
+        }
+        let (mut mass, mut com, mut vcom) = (0., PhysVec { x: 0., y: 0. }, PhysVec { x: 0., y: 0. });
+        for p in particles.iter() {
+            let m = p.source_mass();
+            mass += m;
+            com.x += m * p.pos.x;
+            com.y += m * p.pos.y;
+            vcom.x += m * p.vel.x;
+            vcom.y += m * p.vel.y;
+        }
+        if mass == 0. { return 0 }
+        com = PhysVec { x: com.x / mass, y: com.y / mass };
+        vcom = PhysVec { x: vcom.x / mass, y: vcom.y / mass };
+        let radius = self.radius;
+        let escaping: Vec<bool> = particles.iter().map(|p| {
+            let r = com.diff(p.pos);
+            let dist = r.modulus();
+            if dist <= radius { return false }
+            let v = vcom.diff(p.vel);
+            // the system's own black holes never count as escaping
+            if p.kind == Species::BlackHole || v.dot(&r) <= 0. { return false }
+            0.5 * v.dot(&v) > mass * (HORIZON * radius / dist).ln()
+        }).collect();
+        let count = escaping.iter().filter(|&&e| e).count();
+        if count == 0 { return 0 }
+        for (p, _) in particles.iter().zip(escaping.iter()).filter(|&(_, &e)| e) {
+            match self.log {
+                Some(ref mut out) => writeln!(out, "{} {} {:?} {} {} {} {} {} {}", time, p.id, p.kind, p.origin,
+                                              p.pos.x, p.pos.y, p.vel.x, p.vel.y, p.mass).unwrap(),
+                None => println!("t = {}: particle {} escaped at ({}, {})", time, p.id, p.pos.x, p.pos.y)
+            }
+            match self.action {
+                EscapeAction::Freeze => self.frozen.push(*p),
+                EscapeAction::Remove => (),
+                EscapeAction::Off    => unreachable!()
+            }
+        }
+        let mut k = 0;
+        particles.retain(|_| { k += 1; !escaping[k - 1] });
+        count
+    }
+}
+
+impl Escapes3 {
+    pub fn new(radius: f64, action: EscapeAction, log: Option<&str>) -> Escapes3 {
+        let log = log.map(|path| open_log(path, "posx posy posz velx vely velz mass"));
+        Escapes3 { radius: radius, action: action, log: log, frozen: Vec::new() }
+    }
+
+    //as Escapes::check, against the 1/r potential of the system's mass
+    pub fn check(&mut self, particles: &mut Vec<Particle3>, time: f64) -> usize {
+        match self.action {
+            EscapeAction::Off => return 0,
+            EscapeAction::Remove | EscapeAction::Freeze => ()
+        }
+        let (mut mass, mut com, mut vcom) = (0., Vec3::zero(), Vec3::zero());
+        for p in particles.iter() {
+            let m = p.source_mass();
+            mass += m;
+            com.add(&p.pos.scale(m));
+            vcom.add(&p.vel.scale(m));
+        }
+        if mass == 0. { return 0 }
+        com = com.scale(1. / mass);
+        vcom = vcom.scale(1. / mass);
+        let radius = self.radius;
+        let escaping: Vec<bool> = particles.iter().map(|p| {
+            let r = com.diff(p.pos);
+            let dist = r.modulus();
+            if dist <= radius { return false }
+            let v = vcom.diff(p.vel);
+            if p.kind == Species::BlackHole || v.dot(&r) <= 0. { return false }
+            0.5 * v.dot(&v) > mass / dist
+        }).collect();
+        let count = escaping.iter().filter(|&&e| e).count();
+        if count == 0 { return 0 }
+        for (p, _) in particles.iter().zip(escaping.iter()).filter(|&(_, &e)| e) {
+            match self.log {
+                Some(ref mut out) => writeln!(out, "{} {} {:?} {} {} {} {} {} {} {} {}", time, p.id, p.kind, p.origin,
+                                              p.pos.x, p.pos.y, p.pos.z, p.vel.x, p.vel.y, p.vel.z, p.mass).unwrap(),
+                None => println!("t = {}: particle {} escaped at ({}, {}, {})", time, p.id, p.pos.x, p.pos.y, p.pos.z)
+            }
+            match self.action {
+                EscapeAction::Freeze => self.frozen.push(*p),
+                EscapeAction::Remove => (),
+                EscapeAction::Off    => unreachable!()
+            }
+        }
+        let mut k = 0;
+        particles.retain(|_| { k += 1; !escaping[k - 1] });
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::EscapeAction;
+    use physics::{Particle, PhysVec};
+    use physics3d::{Particle3, Vec3};
+    use super::{Escapes, Escapes3, HORIZON};
+
+    //a unit mass at the origin and four probes at distance r: fast outwards, fast inwards,
+    //slow outwards, and fast outwards but inside the escape radius
+    fn probes(r: f64, fast: f64, slow: f64) -> Vec<(f64, f64)> {
+        vec![(r, fast), (r, -fast), (r, slow), (0.5, fast)]
+    }
+
+    #[test]
+    fn criterion_2d() {
+        // escape energy at r = 2 with radius 1 is M ln(HORIZON / 2)
+        let need = (2. * (HORIZON / 2.).ln()).sqrt();
+        let mut particles = vec![Particle::new(PhysVec { x: 0., y: 0. }, PhysVec { x: 0., y: 0. }, 1.)];
+        for (i, &(r, v)) in probes(2., 1.1 * need, 0.9 * need).iter().enumerate() {
+            let mut p = Particle::new(PhysVec { x: 0., y: r }, PhysVec { x: 0., y: v }, 1e-9);
+            p.id = i as u32 + 1;
+            particles.push(p);
+        }
+        let mut frozen = Escapes::new(1., EscapeAction::Freeze, None);
+        assert_eq!(frozen.check(&mut particles.clone(), 0.), 1);
+        assert_eq!(frozen.frozen.len(), 1);
+        assert_eq!(frozen.frozen[0].id, 1);
+        let mut removed = Escapes::new(1., EscapeAction::Remove, None);
+        let mut left = particles.clone();
+        assert_eq!(removed.check(&mut left, 0.), 1);
+        assert_eq!(left.len(), particles.len() - 1);
+        assert_eq!(removed.frozen.len(), 0);
+        assert_eq!(Escapes::new(1., EscapeAction::Off, None).check(&mut particles, 0.), 0);
+    }
+
+    #[test]
+    fn criterion_3d() {
+        // escape speed at r = 2 is sqrt(2 M / r) = 1
+        let mut particles = vec![Particle3::new(Vec3::zero(), Vec3::zero(), 1.)];
+        for (i, &(r, v)) in probes(2., 1.1, 0.9).iter().enumerate() {
+            let mut p = Particle3::new(Vec3 { x: 0., y: 0., z: r }, Vec3 { x: 0., y: 0., z: v }, 1e-9);
+            p.id = i as u32 + 1;
+            particles.push(p);
+        }
+        let mut escapes = Escapes3::new(1., EscapeAction::Freeze, None);
+        assert_eq!(escapes.check(&mut particles, 0.), 1);
+        assert_eq!(escapes.frozen[0].id, 1);
+        assert_eq!(particles.len(), 4);
+    }
+}
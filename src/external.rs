@@ -0,0 +1,215 @@
+use config::{PotentialCfg, PotentialKind};
+use physics::{Particle, PhysVec, ForceSolver};
+use physics3d::{Particle3, Vec3, ForceSolver3};
+use std::f64;
+
+// Analytic background potentials, such as a rigid dark matter halo, whose pull is added
+// to whatever the gravity solver computes. A potential either sits at a fixed centre or
+// rides along with a galaxy's central particle. The point mass uses the run's own force
+// law (1/r in 2D, 1/r^2 in 3D); the others are the usual 3D forms, cut through z = 0 in 2D.
+
+pub struct Potential {
+    kind: PotentialKind,
+    mass: f64,
+    vcirc: f64,
+    scale: f64,
+    scale_z: f64,
+    centre: Vec3,
+    follow: Option<u32>,      // id of the particle carrying the potential
+    last: usize               // where that particle was last seen
+}
+
+impl Potential {
+    //cfg has been through config::validate, so the kind's parameters are all there
+    pub fn new(cfg: &PotentialCfg, follow: Option<u32>) -> Potential {
+        Potential {
+            kind: cfg.kind,
+            mass: cfg.mass.unwrap_or(0.),
+            vcirc: cfg.vcirc.unwrap_or(0.),
+            scale: cfg.scale.unwrap_or(0.),
+            scale_z: cfg.scale_z.unwrap_or(0.),
+            centre: Vec3 { x: cfg.posx.unwrap_or(0.), y: cfg.posy.unwrap_or(0.), z: cfg.posz.unwrap_or(0.) },
+            follow: follow,
+            last: 0
+        }
+    }
+
+    //index of the followed particle. It only moves when the particles are re-sorted or
+    //some are removed, so look where it was last time before searching
+    fn find<T, F: Fn(&T) -> u32>(&mut self, particles: &Vec<T>, id_of: F) -> Option<usize> {
+        let id = self.follow?;
+        if self.last < particles.len() && id_of(&particles[self.last]) == id {
+            return Some(self.last)
+        }
+        match particles.iter().position(|p| id_of(p) == id) {
+            Some(ix) => { self.last = ix; Some(ix) },
+            None     => None
+        }
+    }
+
+    //acceleration at offset d from the centre
+    fn accel(&self, d: Vec3, dims: u32) -> Vec3 {
+        let r2 = d.dot(&d);
+        if r2 == 0. {
+            return Vec3::zero()
+        }
+        let r = r2.sqrt();
+        match self.kind {
+            PotentialKind::PointMass => {
+                let rn = if dims == 3 { r2 * r } else { r2 };
+                d.scale(-self.mass / rn)
+            },
+            PotentialKind::Logarithmic => d.scale(-self.vcirc * self.vcirc / (r2 + self.scale * self.scale)),
+            PotentialKind::Nfw => {
+                let x = r / self.scale;
+                let enclosed = self.mass * ((1. + x).ln() - x / (1. + x));
+                d.scale(-enclosed / (r2 * r))
+            },
+            PotentialKind::MiyamotoNagai => {
+                let zb = (d.z * d.z + self.scale_z * self.scale_z).sqrt();
+                let az = self.scale + zb;
+                let denom = (d.x * d.x + d.y * d.y + az * az).powf(1.5);
+                let k = -self.mass / denom;
+                Vec3 { x: k * d.x, y: k * d.y, z: if zb > 0. { k * d.z * az / zb } else { 0. } }
+            }
+        }
+    }
+}
+
+// Wraps a 2D solver, adding the potentials' forces to its result
+pub struct External {
+    solver: Box<dyn ForceSolver>,
+    potentials: Vec<Potential>
+}
+
+impl External {
+    pub fn new(solver: Box<dyn ForceSolver>, potentials: Vec<Potential>) -> External {
+        External { solver: solver, potentials: potentials }
+    }
+
+    fn add(&mut self, particles: &Vec<Particle>, frcs: &mut Vec<PhysVec>) {
+        for pot in self.potentials.iter_mut() {
+            match pot.find(particles, |p| p.id) {
+                Some(ix) => pot.centre = Vec3 { x: particles[ix].pos.x, y: particles[ix].pos.y, z: 0. },
+                None     => ()
+            }
+            for (p, f) in particles.iter().zip(frcs.iter_mut()) {
+                let a = pot.accel(Vec3 { x: p.pos.x - pot.centre.x, y: p.pos.y - pot.centre.y, z: 0. }, 2);
+                f.x += a.x * p.mass;
+                f.y += a.y * p.mass;
+            }
+        }
+    }
+}
+
+impl ForceSolver for External {
+    fn forces(&mut self, particles: &Vec<Particle>, frcs: &mut Vec<PhysVec>) {
+        self.solver.forces(particles, frcs);
+        self.add(particles, frcs);
+    }
+
+    fn forces_active(&mut self, particles: &Vec<Particle>, active: &Vec<bool>, frcs: &mut Vec<PhysVec>) {
+        self.solver.forces_active(particles, active, frcs);
+        self.add(particles, frcs);
+    }
+}
+
+pub struct External3 {
+    solver: Box<dyn ForceSolver3>,
+    potentials: Vec<Potential>
+}
+
+impl External3 {
+    pub fn new(solver: Box<dyn ForceSolver3>, potentials: Vec<Potential>) -> External3 {
+        External3 { solver: solver, potentials: potentials }
+    }
+}
+
+impl ForceSolver3 for External3 {
+    fn forces(&mut self, particles: &Vec<Particle3>, frcs: &mut Vec<Vec3>) {
+        self.solver.forces(particles, frcs);
+        for pot in self.potentials.iter_mut() {
+            match pot.find(particles, |p| p.id) {
+                Some(ix) => pot.centre = particles[ix].pos,
+                None     => ()
+            }
+            for (p, f) in particles.iter().zip(frcs.iter_mut()) {
+                let mut d = p.pos;
+                d.sub(&pot.centre);
+                f.add(&pot.accel(d, 3).scale(p.mass));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::{PotentialCfg, PotentialKind};
+    use physics::{Particle, PhysVec, ForceSolver, Classical};
+    use physics::tests::lock;
+    use physics3d::Vec3;
+    use super::{External, Potential};
+
+    fn potential(kind: PotentialKind) -> Potential {
+        let cfg = PotentialCfg { kind: kind, mass: Some(50.), vcirc: Some(3.), scale: Some(2.), scale_z: Some(0.5),
+                                 posx: None, posy: None, posz: None, galaxy: None };
+        Potential::new(&cfg, None)
+    }
+
+    //the accelerations should be minus the gradients of these
+    fn phi(pot: &Potential, d: Vec3) -> f64 {
+        let r = d.dot(&d).sqrt();
+        match pot.kind {
+            PotentialKind::Logarithmic => 0.5 * pot.vcirc * pot.vcirc * (r * r + pot.scale * pot.scale).ln(),
+            PotentialKind::Nfw => -pot.mass * (1. + r / pot.scale).ln() / r,
+            PotentialKind::MiyamotoNagai => {
+                let az = pot.scale + (d.z * d.z + pot.scale_z * pot.scale_z).sqrt();
+                -pot.mass / (d.x * d.x + d.y * d.y + az * az).sqrt()
+            },
+            PotentialKind::PointMass => -pot.mass / r
+        }
+    }
+
+    #[test]
+    fn accelerations_are_potential_gradients() {
+        let h = 1e-5;
+        let points = [Vec3 { x: 0.3, y: -0.2, z: 0.1 }, Vec3 { x: 1.5, y: 2., z: -0.7 },
+                      Vec3 { x: -7., y: 4., z: 3. }, Vec3 { x: 20., y: 0., z: 0.25 }];
+        for &kind in &[PotentialKind::Logarithmic, PotentialKind::Nfw, PotentialKind::MiyamotoNagai,
+                       PotentialKind::PointMass] {
+            let pot = potential(kind);
+            for &d in &points {
+                let a = pot.accel(d, 3);
+                let step = |dx: f64, dy: f64, dz: f64| {
+                    let (up, down) = (Vec3 { x: d.x + dx, y: d.y + dy, z: d.z + dz },
+                                      Vec3 { x: d.x - dx, y: d.y - dy, z: d.z - dz });
+                    -(phi(&pot, up) - phi(&pot, down)) / (2. * h)
+                };
+                let g = Vec3 { x: step(h, 0., 0.), y: step(0., h, 0.), z: step(0., 0., h) };
+                let err = a.diff(g).modulus() / g.modulus();
+                assert!(err < 1e-6, "{:?} at ({}, {}, {}): {} against {}", kind, d.x, d.y, d.z, a.modulus(), g.modulus());
+            }
+        }
+    }
+
+    #[test]
+    fn follows_particle_through_reordering() {
+        let _g = lock();
+        let cfg = PotentialCfg { kind: PotentialKind::PointMass, mass: Some(10.), vcirc: None, scale: None,
+                                 scale_z: None, posx: None, posy: None, posz: None, galaxy: None };
+        let mut ext = External::new(Box::new(Classical), vec![Potential::new(&cfg, Some(7))]);
+        let mut particles: Vec<Particle> = (0..3).map(|i| {
+            let mut p = Particle::new(PhysVec { x: 10. * i as f64, y: 0. }, PhysVec { x: 0., y: 0. }, 1e-9);
+            p.id = 5 + i;
+            p
+        }).collect();
+        let mut frcs = vec![PhysVec { x: 0., y: 0. }; 3];
+        ext.forces(&particles, &mut frcs);
+        // the potential sits on id 7 at x = 20, pulling id 5 at x = 0 along +x with m M / r
+        assert!((frcs[0].x - 1e-9 * 10. / 20.).abs() < 1e-15);
+        particles.swap(0, 2);
+        particles[0].pos.x = 30.;
+        ext.forces(&particles, &mut frcs);
+        assert!((frcs[2].x - 1e-9 * 10. / 30.).abs() < 1e-15);
+    }
+}
@@ -0,0 +1,83 @@
+use complex::Complex;
+use std::f64;
+
+// In-place radix-2 FFTs. Lengths must be powers of two.
+
+pub fn fft(data: &mut [Complex], inverse: bool) {
+    let n = data.len();
+    assert!(n.is_power_of_two());
+    // bit reversal permutation
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j { data.swap(i, j) }
+    }
+    let sign = if inverse { 1. } else { -1. };
+    let mut len = 2;
+    while len <= n {
+        let theta = sign * 2. * f64::consts::PI / len as f64;
+        let wlen = Complex::new(theta.cos(), theta.sin());
+        let mut start = 0;
+        while start < n {
+            let mut w = Complex::new(1., 0.);
+            for k in 0..len / 2 {
+                let u = data[start + k];
+                let v = data[start + k + len / 2] * w;
+                data[start + k] = u + v;
+                data[start + k + len / 2] = u - v;
+                w = w * wlen;
+            }
+            start += len;
+        }
+        len <<= 1;
+    }
+    if inverse {
+        let s = 1. / n as f64;
+        for d in data.iter_mut() {
+            *d = d.scale(s);
+        }
+    }
+}
+
+//transform a row-major n x n grid, rows then columns
+pub fn fft2(data: &mut Vec<Complex>, n: usize, inverse: bool) {
+    assert!(data.len() == n * n);
+    for row in data.chunks_mut(n) {
+        fft(row, inverse);
+    }
+    let mut col = vec![Complex::zero(); n];
+    for x in 0..n {
+        for y in 0..n {
+            col[y] = data[y * n + x];
+        }
+        fft(&mut col, inverse);
+        for y in 0..n {
+            data[y * n + x] = col[y];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use complex::Complex;
+    use super::fft;
+
+    #[test]
+    fn test_fft_roundtrip() {
+        let orig: Vec<Complex> = (0..64).map(|i| Complex::new((i * i % 7) as f64, i as f64 * 0.5)).collect();
+        let mut data = orig.clone();
+        fft(&mut data, false);
+        // the zero mode is the sum of the input
+        let sum = orig.iter().fold(Complex::zero(), |a, &b| a + b);
+        assert!((data[0] - sum).norm_sqr() < 1e-18);
+        fft(&mut data, true);
+        for (a, b) in data.iter().zip(orig.iter()) {
+            assert!((*a - *b).norm_sqr() < 1e-18);
+        }
+    }
+}
@@ -0,0 +1,367 @@
+use physics::{Particle, PhysVec, ForceSolver, force};
+use barneshut::find_bounding_box;
+use complex::Complex;
+use std::{cmp, mem};
+
+// Fast Multipole Method on a uniform quadtree (Greengard & Rokhlin).
+//
+// Treating positions as complex numbers z, the potential of the particles is the real
+// part of Phi(z) = sum m_j log(z - z_j), and the acceleration is -conj(Phi'(z)). Boxes
+// carry truncated multipole expansions of Phi about their centres (built leaf first and
+// shifted up the tree), which are converted into local expansions for well-separated
+// boxes and pushed back down. Leaves then evaluate their local expansion and sum their
+// immediate neighbours directly, giving O(N) work for a fixed expansion order. All the
+// buffers are kept between steps, so once they have grown a step allocates nothing.
+
+// aim for about this many particles per leaf box
+static LEAF_SIZE: usize = 32;
+static MAX_LEVELS: usize = 10;
+
+pub struct Fmm {
+    order: usize,
+    levels: usize,                 // leaves are at level `levels`, the root at 0
+    multipoles: Vec<Vec<Complex>>, // per level, order+1 coefficients per box; [0] is the mass
+    locals: Vec<Vec<Complex>>,     // per level, order+1 coefficients per box; [0] is never used
+    counts: Vec<Vec<usize>>,       // particles in each box, per level
+    leaf_start: Vec<usize>,        // leaf b holds sorted[leaf_start[b]..leaf_start[b+1]]
+    sorted: Vec<usize>,
+    leaves: Vec<usize>,            // leaf of each particle, and the next free slot of each
+    fill: Vec<usize>,              // leaf, while sorting
+    pows: Vec<Complex>,            // scratch for the expansion shifts
+    out: Vec<Complex>,
+    binom: Vec<Vec<f64>>,
+    x0: f64,                       // lower left corner of the root box
+    y0: f64,
+    size: f64
+}
+
+impl Fmm {
+    pub fn new(order: u32) -> Fmm {
+        let p = cmp::max(order as usize, 1);
+        // C(n, k) for n up to 2p covers every shift
+        let mut binom = vec![vec![0.; 2*p + 1]; 2*p + 1];
+        for n in 0..2*p + 1 {
+            binom[n][0] = 1.;
+            for k in 1..n + 1 {
+                binom[n][k] = binom[n-1][k-1] + if k < n { binom[n-1][k] } else { 0. };
+            }
+        }
+        Fmm { order: p, levels: 0, multipoles: Vec::new(), locals: Vec::new(), counts: Vec::new(),
+              leaf_start: Vec::new(), sorted: Vec::new(), leaves: Vec::new(), fill: Vec::new(),
+              pows: Vec::new(), out: Vec::new(), binom: binom, x0: 0., y0: 0., size: 0. }
+    }
+
+    fn centre(&self, level: usize, ix: usize, iy: usize) -> Complex {
+        let w = self.size / (1 << level) as f64;
+        Complex::new(self.x0 + (ix as f64 + 0.5) * w, self.y0 + (iy as f64 + 0.5) * w)
+    }
+
+    fn leaf_of(&self, p: &Particle) -> usize {
+        let n = 1 << self.levels;
+        let w = self.size / n as f64;
+        let ix = cmp::min(((p.pos.x - self.x0) / w) as usize, n - 1);
+        let iy = cmp::min(((p.pos.y - self.y0) / w) as usize, n - 1);
+        iy * n + ix
+    }
+
+    //size the grid for this step and bin the particles into leaves
+    fn setup(&mut self, particles: &Vec<Particle>) {
+        let n = particles.len();
+        let mut levels = 2;
+        while levels < MAX_LEVELS && (1 << (2 * levels)) * LEAF_SIZE < n {
+            levels += 1;
+        }
+        self.levels = levels;
+        let (xmax, xmin, ymax, ymin) = find_bounding_box(particles);
+        self.size = f64::max(f64::max(xmax - xmin, ymax - ymin), 1e-10);
+        self.x0 = xmin;
+        self.y0 = ymin;
+
+        let ncoef = self.order + 1;
+        while self.multipoles.len() < levels + 1 {
+            self.multipoles.push(Vec::new());
+            self.locals.push(Vec::new());
+            self.counts.push(Vec::new());
+        }
+        for l in 0..levels + 1 {
+            let nbox = 1 << (2 * l);
+            reset(&mut self.multipoles[l], nbox * ncoef, Complex::zero());
+            reset(&mut self.locals[l], nbox * ncoef, Complex::zero());
+            reset(&mut self.counts[l], nbox, 0);
+        }
+
+        // counting sort of particle indices by leaf
+        let nleaf = 1 << (2 * levels);
+        let mut leaves = mem::take(&mut self.leaves);
+        leaves.clear();
+        leaves.extend(particles.iter().map(|p| self.leaf_of(p)));
+        for &b in &leaves {
+            self.counts[levels][b] += 1;
+        }
+        self.leaf_start.clear();
+        let mut acc = 0;
+        for b in 0..nleaf {
+            self.leaf_start.push(acc);
+            acc += self.counts[levels][b];
+        }
+        self.leaf_start.push(acc);
+        self.fill.clear();
+        self.fill.extend(self.leaf_start.iter().cloned());
+        self.sorted.clear();
+        self.sorted.extend(0..n);
+        for (i, &b) in leaves.iter().enumerate() {
+            self.sorted[self.fill[b]] = i;
+            self.fill[b] += 1;
+        }
+        self.leaves = leaves;
+        for l in (0..levels).rev() {
+            let side = 1 << l;
+            for iy in 0..side {
+                for ix in 0..side {
+                    let mut c = 0;
+                    for &(cx, cy) in children(ix, iy).iter() {
+                        c += self.counts[l+1][cy * 2 * side + cx];
+                    }
+                    self.counts[l][iy * side + ix] = c;
+                }
+            }
+        }
+    }
+
+    //particle to multipole, at the leaves
+    fn p2m(&mut self, particles: &Vec<Particle>) {
+        let p = self.order;
+        let ncoef = p + 1;
+        let levels = self.levels;
+        let side = 1 << levels;
+        for b in 0..side * side {
+            let zc = self.centre(levels, b % side, b / side);
+            for s in self.leaf_start[b]..self.leaf_start[b+1] {
+                let q = &particles[self.sorted[s]];
+                let d = Complex::new(q.pos.x, q.pos.y) - zc;
+                let coefs = &mut self.multipoles[levels][b * ncoef..(b+1) * ncoef];
+                let m = q.source_mass();
+                coefs[0] = coefs[0] + Complex::new(m, 0.);
+                let mut pow = d;
+                for k in 1..p + 1 {
+                    coefs[k] = coefs[k] - pow.scale(m / k as f64);
+                    pow = pow * d;
+                }
+            }
+        }
+    }
+
+    //shift child multipoles up to their parents
+    fn m2m(&mut self) {
+        let p = self.order;
+        let ncoef = p + 1;
+        let mut pows = mem::take(&mut self.pows);
+        let mut out = mem::take(&mut self.out);
+        for l in (0..self.levels).rev() {
+            let side = 1 << l;
+            for iy in 0..side {
+                for ix in 0..side {
+                    let b = iy * side + ix;
+                    if self.counts[l][b] == 0 { continue }
+                    let zc = self.centre(l, ix, iy);
+                    reset(&mut out, ncoef, Complex::zero());
+                    for &(cx, cy) in children(ix, iy).iter() {
+                        let cb = cy * 2 * side + cx;
+                        if self.counts[l+1][cb] == 0 { continue }
+                        let a = &self.multipoles[l+1][cb * ncoef..(cb+1) * ncoef];
+                        let z0 = self.centre(l+1, cx, cy) - zc;
+                        powers(z0, p, &mut pows);
+                        out[0] = out[0] + a[0];
+                        for m in 1..p + 1 {
+                            let mut bl = (a[0] * pows[m]).scale(-1. / m as f64);
+                            for k in 1..m + 1 {
+                                bl = bl + (a[k] * pows[m-k]).scale(self.binom[m-1][k-1]);
+                            }
+                            out[m] = out[m] + bl;
+                        }
+                    }
+                    for (dst, &src) in self.multipoles[l][b * ncoef..(b+1) * ncoef].iter_mut().zip(out.iter()) {
+                        *dst = src;
+                    }
+                }
+            }
+        }
+        self.pows = pows;
+        self.out = out;
+    }
+
+    //convert the multipole of source box sb (level l) into the local expansion of box tb
+    //(ipows and out are scratch)
+    fn m2l(&mut self, l: usize, sb: usize, tb: usize, zs: Complex, zt: Complex,
+           ipows: &mut Vec<Complex>, out: &mut Vec<Complex>) {
+        let p = self.order;
+        let ncoef = p + 1;
+        let z0 = zs - zt;
+        let inv = z0.inv();
+        powers(inv, 2 * p, ipows);
+        reset(out, ncoef, Complex::zero());
+        {
+            let a = &self.multipoles[l][sb * ncoef..(sb+1) * ncoef];
+            for m in 1..p + 1 {
+                let mut sum = Complex::zero();
+                for k in 1..p + 1 {
+                    let sign = if k % 2 == 0 { 1. } else { -1. };
+                    sum = sum + (a[k] * ipows[k]).scale(sign * self.binom[m+k-1][k-1]);
+                }
+                out[m] = (sum - a[0].scale(1. / m as f64)) * ipows[m];
+            }
+        }
+        for (dst, &src) in self.locals[l][tb * ncoef..(tb+1) * ncoef].iter_mut().zip(out.iter()) {
+            *dst = *dst + src;
+        }
+    }
+
+    //interaction lists and parent-to-child local shifts, top down
+    fn downward(&mut self) {
+        let p = self.order;
+        let ncoef = p + 1;
+        let mut pows = mem::take(&mut self.pows);
+        let mut out = mem::take(&mut self.out);
+        for l in 2..self.levels + 1 {
+            let side = 1 << l;
+            for iy in 0..side {
+                for ix in 0..side {
+                    let tb = iy * side + ix;
+                    if self.counts[l][tb] == 0 { continue }
+                    let zt = self.centre(l, ix, iy);
+                    if l > 2 {
+                        // local expansion of the parent, re-centred on this box
+                        let pb = (iy / 2) * (side / 2) + ix / 2;
+                        let t = zt - self.centre(l-1, ix / 2, iy / 2);
+                        powers(t, p, &mut pows);
+                        reset(&mut out, ncoef, Complex::zero());
+                        {
+                            let a = &self.locals[l-1][pb * ncoef..(pb+1) * ncoef];
+                            for m in 1..p + 1 {
+                                for k in m..p + 1 {
+                                    out[m] = out[m] + (a[k] * pows[k-m]).scale(self.binom[k][m]);
+                                }
+                            }
+                        }
+                        for (dst, &src) in self.locals[l][tb * ncoef..(tb+1) * ncoef].iter_mut().zip(out.iter()) {
+                            *dst = *dst + src;
+                        }
+                    }
+                    // children of the parent's neighbours that are not our neighbours
+                    let (px, py) = (ix / 2, iy / 2);
+                    let xlo = if px > 0 { 2 * (px - 1) } else { 0 };
+                    let ylo = if py > 0 { 2 * (py - 1) } else { 0 };
+                    let xhi = cmp::min(2 * (px + 1) + 1, side - 1);
+                    let yhi = cmp::min(2 * (py + 1) + 1, side - 1);
+                    for jy in ylo..yhi + 1 {
+                        for jx in xlo..xhi + 1 {
+                            if adjacent(ix, iy, jx, jy) { continue }
+                            let sb = jy * side + jx;
+                            if self.counts[l][sb] == 0 { continue }
+                            let zs = self.centre(l, jx, jy);
+                            self.m2l(l, sb, tb, zs, zt, &mut pows, &mut out);
+                        }
+                    }
+                }
+            }
+        }
+        self.pows = pows;
+        self.out = out;
+    }
+
+    //local expansion plus direct sums over neighbouring leaves
+    fn evaluate(&self, particles: &Vec<Particle>, frcs: &mut Vec<PhysVec>) {
+        let p = self.order;
+        let ncoef = p + 1;
+        let levels = self.levels;
+        let side = 1 << levels;
+        for iy in 0..side {
+            for ix in 0..side {
+                let b = iy * side + ix;
+                let zc = self.centre(levels, ix, iy);
+                let local = &self.locals[levels][b * ncoef..(b+1) * ncoef];
+                for s in self.leaf_start[b]..self.leaf_start[b+1] {
+                    let i = self.sorted[s];
+                    let q = &particles[i];
+                    let w = Complex::new(q.pos.x, q.pos.y) - zc;
+                    // Phi'(z) from the local expansion, by Horner's rule
+                    let mut dphi = Complex::zero();
+                    for m in (1..p + 1).rev() {
+                        dphi = dphi * w + local[m].scale(m as f64);
+                    }
+                    let mut f = PhysVec { x: -dphi.re * q.mass, y: dphi.im * q.mass };
+                    let xlo = if ix > 0 { ix - 1 } else { 0 };
+                    let ylo = if iy > 0 { iy - 1 } else { 0 };
+                    for jy in ylo..cmp::min(iy + 2, side) {
+                        for jx in xlo..cmp::min(ix + 2, side) {
+                            let nb = jy * side + jx;
+                            for t in self.leaf_start[nb]..self.leaf_start[nb+1] {
+                                let j = self.sorted[t];
+                                // coincident particles have no direction to pull in
+                                if j == i || particles[j].pos == q.pos { continue }
+                                f.add(&force(q, &particles[j]));
+                            }
+                        }
+                    }
+                    frcs[i] = f;
+                }
+            }
+        }
+    }
+}
+
+impl ForceSolver for Fmm {
+    fn forces(&mut self, particles: &Vec<Particle>, frcs: &mut Vec<PhysVec>) {
+        if particles.len() == 0 { return }
+        self.setup(particles);
+        self.p2m(particles);
+        self.m2m();
+        self.downward();
+        self.evaluate(particles, frcs);
+    }
+}
+
+fn children(ix: usize, iy: usize) -> [(usize, usize); 4] {
+    [(2*ix, 2*iy), (2*ix + 1, 2*iy), (2*ix, 2*iy + 1), (2*ix + 1, 2*iy + 1)]
+}
+
+fn adjacent(ix: usize, iy: usize, jx: usize, jy: usize) -> bool {
+    (ix as isize - jx as isize).abs() <= 1 && (iy as isize - jy as isize).abs() <= 1
+}
+
+//z^0 .. z^n into v
+fn powers(z: Complex, n: usize, v: &mut Vec<Complex>) {
+    v.clear();
+    let mut acc = Complex::new(1., 0.);
+    for _ in 0..n + 1 {
+        v.push(acc);
+        acc = acc * z;
+    }
+}
+
+//set v to len copies of x, reusing its storage
+fn reset<T: Clone>(v: &mut Vec<T>, len: usize, x: T) {
+    v.clear();
+    v.resize(len, x);
+}
+
+#[cfg(test)]
+mod tests {
+    use physics::tests::{lock, random_particles, exact_forces, forces_of, rel_errors};
+    use super::Fmm;
+
+    #[test]
+    fn matches_classical() {
+        let _g = lock();
+        let mut particles = random_particles(2000, 100.);
+        let exact = exact_forces(&particles);
+        let mut fmm = Fmm::new(12);
+        let (mean, _) = rel_errors(&forces_of(&mut fmm, &particles), &exact);
+        assert!(mean < 1e-5, "mean error {}", mean);
+        // a second step reuses the buffers, and coincident particles stay finite
+        let twin = particles[0];
+        particles.push(twin);
+        let frcs = forces_of(&mut fmm, &particles);
+        assert!(frcs.iter().all(|f| f.x.is_finite() && f.y.is_finite()));
+    }
+}
@@ -0,0 +1,126 @@
+use physics::Particle;
+use physics3d::Particle3;
+use barneshut::find_bounding_box;
+use std::f64;
+
+// Ordering particles along a Z-order (Morton) curve puts particles that are close in
+// space close in memory, so consecutive particles walk nearly the same tree path.
+
+//spread the bits of x out so there is a zero between each one
+fn part1by1(x: u32) -> u64 {
+    let mut x = x as u64;
+    x = (x | (x << 16)) & 0x0000ffff0000ffff;
+    x = (x | (x << 8))  & 0x00ff00ff00ff00ff;
+    x = (x | (x << 4))  & 0x0f0f0f0f0f0f0f0f;
+    x = (x | (x << 2))  & 0x3333333333333333;
+    x = (x | (x << 1))  & 0x5555555555555555;
+    x
+}
+
+//interleave the bits of x and y, x taking the even bits
+pub fn interleave(x: u32, y: u32) -> u64 {
+    part1by1(x) | (part1by1(y) << 1)
+}
+
+//spread the low 21 bits of x out so there are two zeros between each one
+fn part1by2(x: u32) -> u64 {
+    let mut x = (x & 0x1fffff) as u64;
+    x = (x | (x << 32)) & 0x001f00000000ffff;
+    x = (x | (x << 16)) & 0x001f0000ff0000ff;
+    x = (x | (x << 8))  & 0x100f00f00f00f00f;
+    x = (x | (x << 4))  & 0x10c30c30c30c30c3;
+    x = (x | (x << 2))  & 0x1249249249249249;
+    x
+}
+
+//interleave the low 21 bits of x, y and z, x taking every third bit from the lowest
+pub fn interleave3(x: u32, y: u32, z: u32) -> u64 {
+    part1by2(x) | (part1by2(y) << 1) | (part1by2(z) << 2)
+}
+
+// keys quantised against the bounding box of a set of particles
+struct Quantiser {
+    xmin: f64,
+    ymin: f64,
+    scale: f64
+}
+
+impl Quantiser {
+    fn new(particles: &Vec<Particle>) -> Quantiser {
+        let (xmax, xmin, ymax, ymin) = find_bounding_box(particles);
+        let scale = (u32::MAX as f64) / f64::max(f64::max(xmax - xmin, ymax - ymin), 1e-300);
+        Quantiser { xmin: xmin, ymin: ymin, scale: scale }
+    }
+
+    fn key(&self, p: &Particle) -> u64 {
+        let qx = ((p.pos.x - self.xmin) * self.scale) as u32;
+        let qy = ((p.pos.y - self.ymin) * self.scale) as u32;
+        interleave(qx, qy)
+    }
+}
+
+//key of each particle, quantised against the bounding box of them all
+#[cfg(test)]
+pub fn morton_keys(particles: &Vec<Particle>) -> Vec<u64> {
+    let q = Quantiser::new(particles);
+    particles.iter().map(|p| q.key(p)).collect()
+}
+
+//keys are cheap enough to recompute in the comparison, which saves a keyed copy per sort
+pub fn sort_particles(particles: &mut Vec<Particle>) {
+    let q = Quantiser::new(particles);
+    particles.sort_by_key(|p| q.key(p));
+}
+
+//as sort_particles, with 21 bits per axis
+pub fn sort_particles3(particles: &mut Vec<Particle3>) {
+    let mut lo = [f64::INFINITY; 3];
+    let mut hi = [f64::NEG_INFINITY; 3];
+    for p in particles.iter() {
+        for (k, &x) in [p.pos.x, p.pos.y, p.pos.z].iter().enumerate() {
+            lo[k] = lo[k].min(x);
+            hi[k] = hi[k].max(x);
+        }
+    }
+    let side = f64::max(f64::max(hi[0] - lo[0], hi[1] - lo[1]), hi[2] - lo[2]);
+    let scale = 0x1fffff as f64 / f64::max(side, 1e-300);
+    let key = |p: &Particle3| interleave3(((p.pos.x - lo[0]) * scale) as u32,
+                                          ((p.pos.y - lo[1]) * scale) as u32,
+                                          ((p.pos.z - lo[2]) * scale) as u32);
+    particles.sort_by_key(|p| key(p));
+}
+
+#[cfg(test)]
+mod tests {
+    use physics::tests::random_particles;
+    use super::{interleave, interleave3, morton_keys, sort_particles};
+
+    #[test]
+    fn test_interleave() {
+        assert!(interleave(0, 0) == 0);
+        assert!(interleave(1, 0) == 1);
+        assert!(interleave(0, 1) == 2);
+        assert!(interleave(3, 3) == 15);
+        assert!(interleave(u32::MAX, 0) == 0x5555555555555555);
+    }
+
+    #[test]
+    fn test_interleave3() {
+        assert!(interleave3(1, 0, 0) == 1);
+        assert!(interleave3(0, 1, 0) == 2);
+        assert!(interleave3(0, 0, 1) == 4);
+        assert!(interleave3(3, 3, 3) == 63);
+        assert!(interleave3(0x1fffff, 0, 0) == 0x1249249249249249);
+    }
+
+    #[test]
+    fn sort_orders_keys_and_keeps_particles() {
+        let mut particles = random_particles(500, 10.);
+        sort_particles(&mut particles);
+        let keys = morton_keys(&particles);
+        assert!(keys.windows(2).all(|w| w[0] <= w[1]));
+        let mut ids: Vec<u32> = particles.iter().map(|p| p.id).collect();
+        ids.sort();
+        assert!(ids == (0..500).collect::<Vec<u32>>());
+    }
+}
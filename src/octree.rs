@@ -0,0 +1,213 @@
+use physics3d::{Particle3, Vec3, ForceSolver3, force};
+use barneshut::{accept, split_by};
+use pool::ThreadPool;
+use std::f64;
+use std::rc::Rc;
+
+// Octree counterpart of barneshut::QuadTree: the same flat node array over a
+// partitioned particle index, with eight consecutive children per split node.
+
+static MAX_DEPTH : u32 = 30;
+const NO_CHILD : usize = 0;
+
+#[derive(Clone, Copy)]
+struct Node3 {
+    centre: Vec3,
+    half: f64,
+    com: Particle3,
+    bmax: f64,
+    start: usize,
+    end: usize,
+    child: usize          // first of eight children, ordered by (z, y, x) octant bits
+}
+
+impl Node3 {
+    fn contains(&self, pos: &Vec3) -> bool {
+        (pos.x - self.centre.x).abs() <= self.half &&
+        (pos.y - self.centre.y).abs() <= self.half &&
+        (pos.z - self.centre.z).abs() <= self.half
+    }
+}
+
+pub struct OctTree {
+    nodes: Vec<Node3>,
+    index: Vec<usize>
+}
+
+impl OctTree {
+    pub fn new() -> OctTree {
+        OctTree { nodes: Vec::new(), index: Vec::new() }
+    }
+
+    pub fn build(&mut self, particles: &Vec<Particle3>) {
+        self.nodes.clear();
+        self.index.clear();
+        self.index.extend(0..particles.len());
+        if particles.len() == 0 { return }
+        let mut lo = Vec3 { x: f64::INFINITY, y: f64::INFINITY, z: f64::INFINITY };
+        let mut hi = Vec3 { x: f64::NEG_INFINITY, y: f64::NEG_INFINITY, z: f64::NEG_INFINITY };
+        for p in particles {
+            lo = Vec3 { x: lo.x.min(p.pos.x), y: lo.y.min(p.pos.y), z: lo.z.min(p.pos.z) };
+            hi = Vec3 { x: hi.x.max(p.pos.x), y: hi.y.max(p.pos.y), z: hi.z.max(p.pos.z) };
+        }
+        let centre = Vec3 { x: (lo.x + hi.x) / 2., y: (lo.y + hi.y) / 2., z: (lo.z + hi.z) / 2. };
+        let half = f64::max(f64::max(hi.x - lo.x, hi.y - lo.y), hi.z - lo.z) / 2.;
+        self.nodes.push(empty_node(centre, half));
+        self.make_node(particles, 0, 0, particles.len(), centre, half, 0);
+    }
+
+    fn make_node(&mut self, particles: &Vec<Particle3>, ix: usize, start: usize, end: usize,
+                 centre: Vec3, half: f64, depth: u32) {
+        let mut node = empty_node(centre, half);
+        node.start = start;
+        node.end = end;
+        let mut msum = Vec3::zero();
+        let mut mass = 0.;
+        for &i in &self.index[start..end] {
+            let p = &particles[i];
+            msum.add(&p.pos.scale(p.source_mass()));
+            mass += p.source_mass();
+        }
+        if mass > 0. {
+            node.com = Particle3::new(msum.scale(1. / mass), Vec3::zero(), mass);
+            let c = node.com.pos;
+            let dx = (c.x - centre.x).abs() + half;
+            let dy = (c.y - centre.y).abs() + half;
+            let dz = (c.z - centre.z).abs() + half;
+            node.bmax = (dx*dx + dy*dy + dz*dz).sqrt();
+        }
+        self.nodes[ix] = node;
+        if end - start < 2 || depth >= MAX_DEPTH {
+            return
+        }
+        // split on z, then each half on y, then each quarter on x; low sides first
+        let mut bounds = vec![start, end];
+        for axis in 0..3 {
+            let mut next = Vec::with_capacity(2 * bounds.len() - 1);
+            for w in 0..bounds.len() - 1 {
+                let (s, e) = (bounds[w], bounds[w+1]);
+                let mid = s + split_by(&mut self.index[s..e], |i| {
+                    let p = particles[i].pos;
+                    match axis { 0 => p.z <= centre.z, 1 => p.y <= centre.y, _ => p.x <= centre.x }
+                });
+                next.push(s);
+                next.push(mid);
+            }
+            next.push(end);
+            bounds = next;
+        }
+        let child = self.nodes.len();
+        for _ in 0..8 {
+            self.nodes.push(empty_node(centre, half));
+        }
+        self.nodes[ix].child = child;
+        let h = half / 2.;
+        for k in 0..8 {
+            let sign = |bit: usize| if k & bit != 0 { h } else { -h };
+            let c = Vec3 { x: centre.x + sign(1), y: centre.y + sign(2), z: centre.z + sign(4) };
+            self.make_node(particles, child + k, bounds[k], bounds[k+1], c, h, depth + 1);
+        }
+    }
+
+    pub fn force(&self, particles: &Vec<Particle3>, p: &Particle3) -> Vec3 {
+        if self.nodes.len() == 0 {
+            return Vec3::zero()
+        }
+        self.bh_force(particles, p, 0)
+    }
+
+    fn bh_force(&self, particles: &Vec<Particle3>, p: &Particle3, ix: usize) -> Vec3 {
+        let node = &self.nodes[ix];
+        let mut tot_force = Vec3::zero();
+        if node.start == node.end {
+            return tot_force
+        }
+        if node.child == NO_CHILD {
+            for &j in &self.index[node.start..node.end] {
+                let q = &particles[j];
+                if q != p { tot_force.add(&force(p, q)) }
+            }
+            return tot_force
+        }
+        let dist = p.pos.diff(node.com.pos).modulus();
+        let monopole = node.com.mass / (dist * dist);
+        if accept(dist, 2. * node.half, node.bmax, monopole, p.acc.modulus(), node.contains(&p.pos)) {
+            force(p, &node.com)
+        } else {
+            for c in node.child..node.child + 8 {
+                tot_force.add(&self.bh_force(particles, p, c))
+            }
+            tot_force
+        }
+    }
+}
+
+fn empty_node(centre: Vec3, half: f64) -> Node3 {
+    Node3 { centre: centre, half: half, com: Particle3::new(centre, Vec3::zero(), 0.), bmax: 0.,
+            start: 0, end: 0, child: NO_CHILD }
+}
+
+// Barnes-Hut in 3D. With a pool the walks are shared out over its threads.
+pub struct BarnesHut3 {
+    tree: OctTree,
+    pool: Option<Rc<ThreadPool>>
+}
+
+impl BarnesHut3 {
+    pub fn new(pool: Option<Rc<ThreadPool>>) -> BarnesHut3 {
+        BarnesHut3 { tree: OctTree::new(), pool: pool }
+    }
+}
+
+impl ForceSolver3 for BarnesHut3 {
+    fn forces(&mut self, particles: &Vec<Particle3>, frcs: &mut Vec<Vec3>) {
+        self.tree.build(particles);
+        let tree = &self.tree;
+        match self.pool {
+            None => for (p, f) in particles.iter().zip(frcs.iter_mut()) {
+                *f = tree.force(particles, p);
+            },
+            Some(ref pool) => {
+                pool.run_mut(frcs, pool.chunk_for(particles.len()), &|start, out: &mut [Vec3]| {
+                    for (f, p) in out.iter_mut().zip(particles[start..].iter()) {
+                        *f = tree.force(particles, p);
+                    }
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use barneshut::THRESH;
+    use physics::tests::lock;
+    use physics3d::Classical3;
+    use physics3d::tests::{random_particles3, forces_of3};
+    use pool::ThreadPool;
+    use super::BarnesHut3;
+
+    #[test]
+    fn matches_classical() {
+        let _g = lock();
+        let particles = random_particles3(1000, 100.);
+        let exact = forces_of3(&mut Classical3, &particles);
+        let mean_error = |thresh: f64| {
+            unsafe { THRESH = thresh };
+            let frcs = forces_of3(&mut BarnesHut3::new(None), &particles);
+            let total: f64 = frcs.iter().zip(exact.iter())
+                .map(|(f, e)| f.diff(*e).modulus() / e.modulus()).sum();
+            total / particles.len() as f64
+        };
+        let (coarse, fine) = (mean_error(1.), mean_error(4.));
+        assert!(coarse < 5e-2, "mean error {} at threshold 1", coarse);
+        assert!(fine < 5e-3 && fine < coarse, "mean error {} at threshold 4", fine);
+        // the pooled walks are the same walks
+        let serial = forces_of3(&mut BarnesHut3::new(None), &particles);
+        let pooled = forces_of3(&mut BarnesHut3::new(Some(Rc::new(ThreadPool::new(4)))), &particles);
+        for (a, b) in serial.iter().zip(pooled.iter()) {
+            assert_eq!((a.x, a.y, a.z), (b.x, b.y, b.z));
+        }
+    }
+}
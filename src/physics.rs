@@ -1,16 +1,29 @@
 use std::{f64, fmt};
-use config::{GalaxyCfg, GalaxyShape};
+use config::{GalaxyCfg, GalaxyShape, Species};
 use config;
 use rand;
 
 pub static mut DT: f64 = 0.05;
 static EPS: f64 = 0.;
 
+// side of the periodic box, which is centred on the origin; 0 for open boundaries
+pub static mut BOX_SIZE: f64 = 0.;
+
 #[derive(PartialEq, Clone, Copy)]
 pub struct Particle {
     pub pos : PhysVec,
     pub vel : PhysVec,
-    pub mass: f64
+    pub acc : PhysVec,              // acceleration from the previous step
+    pub mass: f64,
+    pub id  : u32,                  // stable across reordering, assigned at startup
+    pub kind: Species,
+    pub origin: u32,                // index of the galaxy or field the particle came from
+    pub level: u32,                 // block timestep level, the particle steps by dt/2^level
+    pub radius: f64,                // size for hard-sphere collisions, 0 for a point mass
+                                    // the rest is only used for gas
+    pub u   : f64,                  // specific internal energy
+    pub rho : f64,                  // density at the last hydro step
+    pub h   : f64                   // smoothing length, 0 until the first hydro step
 }
 
 #[derive(PartialEq, Copy, Clone)]
@@ -25,6 +38,11 @@ impl PhysVec {
         self.y += other.y;
     }
 
+    pub fn sub(&mut self, other: &PhysVec) {
+        self.x -= other.x;
+        self.y -= other.y;
+    }
+
     pub fn dot(&self, other: &PhysVec) -> f64 {
     self.x * other.x + self.y * other.y
     }
@@ -38,6 +56,22 @@ impl PhysVec {
         PhysVec { x: v2.x - self.x, y: v2.y -self.y }
     }
 
+    //as diff, but to the nearest periodic image of v2
+    pub fn separation(&self, v2: PhysVec) -> PhysVec {
+        let d = self.diff(v2);
+        let l = unsafe { BOX_SIZE };
+        if l == 0. { return d }
+        PhysVec { x: d.x - l * (d.x / l).round(), y: d.y - l * (d.y / l).round() }
+    }
+
+    //move back into the periodic box
+    pub fn wrap(&mut self) {
+        let l = unsafe { BOX_SIZE };
+        if l == 0. { return }
+        self.x -= l * (self.x / l + 0.5).floor();
+        self.y -= l * (self.y / l + 0.5).floor();
+    }
+
     fn angle(&self) -> f64 {
         let angle = (self.y/self.x).atan();
         if self.y > 0. && self.x < 0. {
@@ -53,25 +87,38 @@ impl PhysVec {
 }
 
 impl Particle {
+    pub fn new(pos: PhysVec, vel: PhysVec, mass: f64) -> Particle {
+        Particle { pos: pos, vel: vel, acc: PhysVec {x: 0., y: 0.}, mass: mass, id: 0, kind: Species::Star, origin: 0,
+                   level: 0, radius: 0., u: 0., rho: 0., h: 0. }
+    }
+
+    //mass as seen by other particles; test particles feel gravity without sourcing it
+    pub fn source_mass(&self) -> f64 {
+        if self.kind == Species::Test { 0. } else { self.mass }
+    }
+
     fn kinetic_energy(&self) -> f64 {
         0.5 * ((self.vel.x * self.vel.x) + (self.vel.y * self.vel.y)) * self.mass
     }
 
     pub fn steppos(&mut self) {
         unsafe {
-            self.pos.x = self.pos.x + self.vel.x*DT;
-            self.pos.y = self.pos.y + self.vel.y*DT;
+            self.pos.x += self.vel.x*DT;
+            self.pos.y += self.vel.y*DT;
         }
+        self.pos.wrap();
     }
 
 }
 
 
 //force is calculated as pointing from particle 1 towards particle 2
+//coincident particles exert no force on each other, as in the tiled and fmm sums
 pub fn force(p1: &Particle, p2: &Particle) -> PhysVec {
-    let disp = p1.pos.diff(p2.pos);
+    let disp = p1.pos.separation(p2.pos);
     let dist = disp.modulus() + EPS;
-    let f = p1.mass * p2.mass / dist; // force magnitude
+    if dist == 0. { return PhysVec { x: 0., y: 0. } }
+    let f = p1.mass * p2.source_mass() / dist; // force magnitude
     PhysVec { x: f*disp.x/dist, y: f*disp.y/dist }
 }
 
@@ -88,9 +135,9 @@ fn spawn_circular_galaxy(max_radius: f64, nrings: u32, num_bodys: u32) -> Vec<Pa
     for (&n, &r) in nbody.iter().zip(radii.iter()) {
         for i in 1..(n+1) {
             let theta = (i as f64)/(n as f64)*2.0*pi;
-            particles.push(Particle {pos:PhysVec {x: r*theta.cos(), y: r*theta.sin() }, 
-                                     vel:PhysVec {x: 0., y: 0.},
-                                     mass:1. });
+            particles.push(Particle::new(PhysVec {x: r*theta.cos(), y: r*theta.sin() },
+                                         PhysVec {x: 0., y: 0.},
+                                         1.));
         }
     }
     particles
@@ -104,9 +151,9 @@ fn spawn_random_galaxy_weighted(radius: f64, num_bodys: u32) -> Vec<Particle> {
         let r = rand::random::<f64>()*radius;
         let x =  r*theta.cos();
         let y =  r*theta.sin();
-        particles.push(Particle {pos:PhysVec {x: x,  y: y },
-                                 vel:PhysVec {x: 0., y: 0.},
-                                 mass:1. });
+        particles.push(Particle::new(PhysVec {x: x,  y: y },
+                                     PhysVec {x: 0., y: 0.},
+                                     1.));
     }
     particles
 }
@@ -119,9 +166,9 @@ fn spawn_random_galaxy_even(radius: f64, num_bodys: u32) -> Vec<Particle> {
         let x = (rand::random::<f64>() - 0.5)*2.0*radius;
         let y = (rand::random::<f64>() - 0.5)*2.0*radius;
         if x*x + y*y < r2 {
-            particles.push(Particle {pos:PhysVec {x: x,  y: y },
-                                     vel:PhysVec {x: 0., y: 0.},
-                                     mass:1. });
+            particles.push(Particle::new(PhysVec {x: x,  y: y },
+                                         PhysVec {x: 0., y: 0.},
+                                         1.));
         }
         ct +=1;
     }
@@ -130,23 +177,49 @@ fn spawn_random_galaxy_even(radius: f64, num_bodys: u32) -> Vec<Particle> {
 
 fn galilean_offset(particles: &mut Vec<Particle>, central_pcl: &Particle) {
     //offset all particles by given position velocity
-    for mut p in particles {
+    for p in particles {
         p.pos.add(&central_pcl.pos);
         p.vel.add(&central_pcl.vel);
     }
 }
 
+//bodies of a flat galaxy centred on the origin, at rest
+pub fn spawn_disk(shape: GalaxyShape, radius: f64, nbody: u32) -> Vec<Particle> {
+    match shape {
+        GalaxyShape::RandomWeighted => spawn_random_galaxy_weighted(radius, nbody),
+        GalaxyShape::RandomEven => spawn_random_galaxy_even(radius, nbody),
+        GalaxyShape::Concentric(nrings) => spawn_circular_galaxy(radius, nrings, nbody),
+        GalaxyShape::Spheroid(_) => panic!("spheroid galaxies need dimensions = 3")
+    }
+}
+
 pub fn make_galaxy(gal: GalaxyCfg) -> Vec<Particle> {
-    let central_pcl = Particle { 
-        pos: PhysVec { x: gal.posx.unwrap(), y: gal.posy.unwrap() },
-        vel: PhysVec { x: gal.velx.unwrap(), y: gal.vely.unwrap() },
-        mass: gal.central_mass.unwrap()
-    };
-    let mut particles = match gal.shape.unwrap() {
-        config::GalaxyShape::RandomWeighted => spawn_random_galaxy_weighted(gal.radius.unwrap(), gal.nbody),
-        config::GalaxyShape::RandomEven => spawn_random_galaxy_even(gal.radius.unwrap(), gal.nbody),
-        config::GalaxyShape::Concentric(nrings) => spawn_circular_galaxy(gal.radius.unwrap(), nrings, gal.nbody)
-    };
+    let central_pcl = Particle::new(
+        PhysVec { x: gal.posx.unwrap(), y: gal.posy.unwrap() },
+        PhysVec { x: gal.velx.unwrap(), y: gal.vely.unwrap() },
+        gal.central_mass.unwrap()
+    );
+    let mut central_pcl = central_pcl;
+    central_pcl.radius = gal.central_radius.unwrap_or(0.);
+    let mut particles = spawn_disk(gal.shape.unwrap(), gal.radius.unwrap(), gal.nbody);
+    central_pcl.kind = gal.central_species.unwrap_or(Species::Star);
+    for p in particles.iter_mut() {
+        p.radius = gal.body_radius.unwrap_or(0.);
+        p.kind = gal.species.unwrap_or(Species::Star);
+        if rand::random::<f64>() < gal.gas_fraction.unwrap_or(0.) {
+            p.kind = Species::Gas;
+        }
+    }
+    // gas from species or central_species warms up just like gas from gas_fraction
+    let u = gal.gas_energy.unwrap_or(0.);
+    for p in particles.iter_mut() {
+        if p.kind == Species::Gas { p.u = u }
+    }
+    if central_pcl.kind == Species::Gas { central_pcl.u = u }
+    for mut p in spawn_disk(gal.shape.unwrap(), gal.radius.unwrap(), gal.tracers.unwrap_or(0)) {
+        p.kind = Species::Test;
+        particles.push(p);
+    }
 
     match gal.kinetics.unwrap() {
         config::GalaxyKinetics::ZeroVel               => (),
@@ -172,12 +245,13 @@ fn init_circular_orbits(particles: &mut Vec<Particle>, central_mass: f64) {
     //Calculate force and velocities to create a circular orbit
     let mut vels : Vec<PhysVec> = Vec::new();
     // need to make dummy since we are initialising centred on zero
-    let dummy_central_pcl = Particle { pos: PhysVec {x:0., y:0.},
-                                       vel: PhysVec {x:0., y:0.},
-                                       mass: central_mass };
+    let dummy_central_pcl = Particle::new(PhysVec {x:0., y:0.},
+                                          PhysVec {x:0., y:0.},
+                                          central_mass);
+    let sources: Vec<Particle> = particles.iter().filter(|q| q.kind != Species::Test).cloned().collect();
     for p in particles.iter() {
         let mut forcev = PhysVec {x : 0., y: 0.};
-        for q in particles.iter() {
+        for q in sources.iter() {
             if q != p {
                 forcev.add(&force(p, q))
             }
@@ -210,28 +284,54 @@ pub fn stepvel(p: &mut Particle, force: PhysVec, sense:bool) {
     }
 }
 
-pub fn stepsim(particles: &mut Vec<Particle>) {
-    let lenp = particles.len();
-    for i in 0..lenp {
-        for j in i+1..lenp {
-            if i != j {
+// A way of computing the gravitational force on every particle. Solvers are kept for
+// the whole run, so they can hold on to buffers (trees, thread pools) between steps
+pub trait ForceSolver {
+    //write the force on particles[i] into frcs[i]
+    fn forces(&mut self, particles: &Vec<Particle>, frcs: &mut Vec<PhysVec>);
+
+    //as forces, but only frcs[i] with active[i] set need be written
+    fn forces_active(&mut self, particles: &Vec<Particle>, active: &Vec<bool>, frcs: &mut Vec<PhysVec>) {
+        let _ = active;
+        self.forces(particles, frcs)
+    }
+}
+
+//direct O(N^2) summation
+pub struct Classical;
+
+impl ForceSolver for Classical {
+    fn forces(&mut self, particles: &Vec<Particle>, frcs: &mut Vec<PhysVec>) {
+        for f in frcs.iter_mut() {
+            *f = PhysVec {x: 0., y: 0.};
+        }
+        // test particles only feel the sources, and nothing feels them
+        let (sources, tracers): (Vec<usize>, Vec<usize>) =
+            (0..particles.len()).partition(|&i| particles[i].kind != Species::Test);
+        for (k, &i) in sources.iter().enumerate() {
+            for &j in &sources[k+1..] {
                 let f = force(&particles[i], &particles[j]);
-                stepvel(&mut particles[i], f, true);
-                stepvel(&mut particles[j], f, false);
+                frcs[i].add(&f);
+                frcs[j].sub(&f);
+            }
+        }
+        for &t in &tracers {
+            for &j in &sources {
+                frcs[t].add(&force(&particles[t], &particles[j]));
             }
         }
-    }
-    for p in particles {
-        p.steppos();
     }
 }
 
-fn total_ke(particles: &Vec<Particle>) -> f64 {
-    let mut ke = 0.;
-    for p in particles {
-        ke += p.kinetic_energy()
+//frcs is scratch space kept by the caller so that steps don't allocate
+pub fn stepsim(particles: &mut Vec<Particle>, solver: &mut dyn ForceSolver, frcs: &mut Vec<PhysVec>) {
+    frcs.resize(particles.len(), PhysVec {x: 0., y: 0.});
+    solver.forces(particles, frcs);
+    for (p, &f) in particles.iter_mut().zip(frcs.iter()) {
+        p.acc = PhysVec { x: f.x/p.mass, y: f.y/p.mass };
+        stepvel(p, f, true);
+        p.steppos();
     }
-    ke
 }
 
 impl fmt::Display for Particle {
@@ -241,3 +341,86 @@ impl fmt::Display for Particle {
             self.kinetic_energy())
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::{Mutex, MutexGuard};
+    use rand;
+    use barneshut;
+    use config::OpeningCriterion;
+    use super::{Particle, PhysVec, ForceSolver, Classical, DT, BOX_SIZE};
+
+    // the solvers read their parameters from statics, so tests take turns with them
+    static GLOBALS: Mutex<()> = Mutex::new(());
+
+    // holds the statics until dropped, and puts back their defaults either side
+    pub struct Globals {
+        _guard: MutexGuard<'static, ()>
+    }
+
+    impl Drop for Globals {
+        fn drop(&mut self) {
+            reset()
+        }
+    }
+
+    fn reset() {
+        unsafe {
+            DT = 0.05;
+            BOX_SIZE = 0.;
+            barneshut::THRESH = 1.0;
+            barneshut::CRITERION = OpeningCriterion::Geometric;
+        }
+    }
+
+    pub fn lock() -> Globals {
+        let guard = GLOBALS.lock().unwrap_or_else(|e| e.into_inner());
+        reset();
+        Globals { _guard: guard }
+    }
+
+    //n particles with masses in [0.5, 1.5) scattered over a square of side `side` at the origin
+    pub fn random_particles(n: usize, side: f64) -> Vec<Particle> {
+        (0..n).map(|i| {
+            let pos = PhysVec { x: (rand::random::<f64>() - 0.5) * side, y: (rand::random::<f64>() - 0.5) * side };
+            let mut p = Particle::new(pos, PhysVec { x: 0., y: 0. }, 0.5 + rand::random::<f64>());
+            p.id = i as u32;
+            p
+        }).collect()
+    }
+
+    pub fn forces_of(solver: &mut dyn ForceSolver, particles: &Vec<Particle>) -> Vec<PhysVec> {
+        let mut frcs = vec![PhysVec { x: 0., y: 0. }; particles.len()];
+        solver.forces(particles, &mut frcs);
+        frcs
+    }
+
+    pub fn exact_forces(particles: &Vec<Particle>) -> Vec<PhysVec> {
+        forces_of(&mut Classical, particles)
+    }
+
+    #[test]
+    fn gas_species_gets_gas_energy() {
+        use config::{GalaxyCfg, GalaxyShape, GalaxyKinetics, Species};
+        let gal = GalaxyCfg { posx: Some(0.), posy: Some(0.), velx: Some(0.), vely: Some(0.), radius: Some(100.),
+                              nbody: 50, shape: Some(GalaxyShape::RandomWeighted), kinetics: Some(GalaxyKinetics::ZeroVel),
+                              central_mass: Some(10.), other_mass: Some(1.), posz: None, velz: None, thickness: None,
+                              inclination: None, central_radius: None, body_radius: None, species: Some(Species::Gas),
+                              central_species: None, tracers: None, gas_fraction: None, gas_energy: Some(2.5) };
+        let particles = super::make_galaxy(gal);
+        assert!(particles.iter().all(|p| if p.kind == Species::Gas { p.u == 2.5 } else { p.u == 0. }));
+        assert!(particles.iter().filter(|p| p.kind == Species::Gas).count() == 50);
+    }
+
+    //mean and largest of |f - exact| / |exact|
+    pub fn rel_errors(frcs: &Vec<PhysVec>, exact: &Vec<PhysVec>) -> (f64, f64) {
+        let mut sum = 0.;
+        let mut max: f64 = 0.;
+        for (f, e) in frcs.iter().zip(exact.iter()) {
+            let err = f.diff(*e).modulus() / e.modulus();
+            sum += err;
+            max = max.max(err);
+        }
+        (sum / frcs.len() as f64, max)
+    }
+}
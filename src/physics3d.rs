@@ -0,0 +1,290 @@
+use std::f64;
+use config::{GalaxyCfg, GalaxyShape, GalaxyKinetics, Species};
+use physics;
+use physics::DT;
+use rand;
+
+// Three dimensional counterparts of the types in `physics`, with a Newtonian 1/r^2
+// force. Galaxies are generated as disks of configurable thickness and inclination,
+// or as spheroids.
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct Vec3 {
+    pub x : f64,
+    pub y : f64,
+    pub z : f64
+}
+
+impl Vec3 {
+    pub fn zero() -> Vec3 {
+        Vec3 { x: 0., y: 0., z: 0. }
+    }
+
+    pub fn add(&mut self, other: &Vec3) {
+        self.x += other.x;
+        self.y += other.y;
+        self.z += other.z;
+    }
+
+    pub fn sub(&mut self, other: &Vec3) {
+        self.x -= other.x;
+        self.y -= other.y;
+        self.z -= other.z;
+    }
+
+    pub fn scale(&self, s: f64) -> Vec3 {
+        Vec3 { x: self.x * s, y: self.y * s, z: self.z * s }
+    }
+
+    pub fn dot(&self, other: &Vec3) -> f64 {
+        self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    pub fn cross(&self, other: &Vec3) -> Vec3 {
+        Vec3 { x: self.y * other.z - self.z * other.y,
+               y: self.z * other.x - self.x * other.z,
+               z: self.x * other.y - self.y * other.x }
+    }
+
+    pub fn modulus(&self) -> f64 {
+        self.dot(self).sqrt()
+    }
+
+    //vector pointing from v1 towards v2
+    pub fn diff(&self, v2: Vec3) -> Vec3 {
+        Vec3 { x: v2.x - self.x, y: v2.y - self.y, z: v2.z - self.z }
+    }
+
+    //rotate by angle (radians) about the x axis
+    pub fn tilt(&self, angle: f64) -> Vec3 {
+        let (s, c) = angle.sin_cos();
+        Vec3 { x: self.x, y: c * self.y - s * self.z, z: s * self.y + c * self.z }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
+pub struct Particle3 {
+    pub pos : Vec3,
+    pub vel : Vec3,
+    pub acc : Vec3,
+    pub mass: f64,
+    pub id  : u32,
+    pub kind: Species,
+    pub origin: u32
+}
+
+impl Particle3 {
+    pub fn new(pos: Vec3, vel: Vec3, mass: f64) -> Particle3 {
+        Particle3 { pos: pos, vel: vel, acc: Vec3::zero(), mass: mass, id: 0, kind: Species::Star, origin: 0 }
+    }
+
+    //mass as seen by other particles; test particles feel gravity without sourcing it
+    pub fn source_mass(&self) -> f64 {
+        if self.kind == Species::Test { 0. } else { self.mass }
+    }
+
+    pub fn steppos(&mut self) {
+        let dt = unsafe { DT };
+        self.pos.add(&self.vel.scale(dt));
+    }
+}
+
+//force is calculated as pointing from particle 1 towards particle 2
+pub fn force(p1: &Particle3, p2: &Particle3) -> Vec3 {
+    let disp = p1.pos.diff(p2.pos);
+    let dist2 = disp.dot(&disp);
+    if dist2 == 0. { return Vec3::zero() }
+    disp.scale(p1.mass * p2.source_mass() / (dist2 * dist2.sqrt()))
+}
+
+pub trait ForceSolver3 {
+    //write the force on particles[i] into frcs[i]
+    fn forces(&mut self, particles: &Vec<Particle3>, frcs: &mut Vec<Vec3>);
+}
+
+//direct O(N^2) summation
+pub struct Classical3;
+
+impl ForceSolver3 for Classical3 {
+    fn forces(&mut self, particles: &Vec<Particle3>, frcs: &mut Vec<Vec3>) {
+        for f in frcs.iter_mut() {
+            *f = Vec3::zero();
+        }
+        // test particles only feel the sources, and nothing feels them
+        let (sources, tracers): (Vec<usize>, Vec<usize>) =
+            (0..particles.len()).partition(|&i| particles[i].kind != Species::Test);
+        for (k, &i) in sources.iter().enumerate() {
+            for &j in &sources[k+1..] {
+                let f = force(&particles[i], &particles[j]);
+                frcs[i].add(&f);
+                frcs[j].sub(&f);
+            }
+        }
+        for &t in &tracers {
+            for &j in &sources {
+                frcs[t].add(&force(&particles[t], &particles[j]));
+            }
+        }
+    }
+}
+
+pub fn stepsim(particles: &mut Vec<Particle3>, solver: &mut dyn ForceSolver3, frcs: &mut Vec<Vec3>) {
+    frcs.resize(particles.len(), Vec3::zero());
+    solver.forces(particles, frcs);
+    let dt = unsafe { DT };
+    for (p, f) in particles.iter_mut().zip(frcs.iter()) {
+        p.acc = f.scale(1. / p.mass);
+        p.vel.add(&p.acc.scale(dt));
+        p.steppos();
+    }
+}
+
+//standard normal deviate, by Box-Muller
+pub fn gaussian() -> f64 {
+    let u1 = 1. - rand::random::<f64>();
+    let u2 = rand::random::<f64>();
+    (-2. * u1.ln()).sqrt() * (2. * f64::consts::PI * u2).cos()
+}
+
+fn random_unit() -> Vec3 {
+    let z = 2. * rand::random::<f64>() - 1.;
+    let phi = 2. * f64::consts::PI * rand::random::<f64>();
+    let r = (1. - z * z).sqrt();
+    Vec3 { x: r * phi.cos(), y: r * phi.sin(), z: z }
+}
+
+fn spawn_spheroid(radius: f64, flattening: f64, num_bodys: u32) -> Vec<Particle3> {
+    let mut particles = Vec::new();
+    while particles.len() < num_bodys as usize {
+        let v = Vec3 { x: 2. * rand::random::<f64>() - 1.,
+                       y: 2. * rand::random::<f64>() - 1.,
+                       z: 2. * rand::random::<f64>() - 1. };
+        if v.dot(&v) < 1. {
+            let pos = Vec3 { x: v.x * radius, y: v.y * radius, z: v.z * radius * flattening };
+            particles.push(Particle3::new(pos, Vec3::zero(), 1.));
+        }
+    }
+    particles
+}
+
+fn init_circular_orbits(particles: &mut Vec<Particle3>, central_mass: f64, in_plane: bool) {
+    // forces from the other bodies and the central mass, which sits at the origin for now
+    let mut sources: Vec<Particle3> = particles.iter().filter(|q| q.kind != Species::Test).cloned().collect();
+    sources.push(Particle3::new(Vec3::zero(), Vec3::zero(), central_mass));
+    let frcs: Vec<Vec3> = particles.iter().map(|p| {
+        let mut f = Vec3::zero();
+        for q in sources.iter() {
+            if q.pos != p.pos { f.add(&force(p, q)) }
+        }
+        f
+    }).collect();
+    for (p, f) in particles.iter_mut().zip(frcs.iter()) {
+        let r = p.pos.modulus();
+        if r == 0. { continue }
+        // centripetal balance against the inward component of the force
+        let inward = -f.dot(&p.pos) / r;
+        let speed = (f64::max(inward, 0.) * r / p.mass).sqrt();
+        let dir = if in_plane {
+            Vec3 { x: p.pos.y, y: -p.pos.x, z: 0. }
+        } else {
+            p.pos.cross(&random_unit())
+        };
+        let norm = dir.modulus();
+        if norm > 0. {
+            p.vel = dir.scale(speed / norm);
+        }
+    }
+}
+
+pub fn make_galaxy(gal: GalaxyCfg) -> Vec<Particle3> {
+    let central_pcl = Particle3::new(
+        Vec3 { x: gal.posx.unwrap(), y: gal.posy.unwrap(), z: gal.posz.unwrap_or(0.) },
+        Vec3 { x: gal.velx.unwrap(), y: gal.vely.unwrap(), z: gal.velz.unwrap_or(0.) },
+        gal.central_mass.unwrap()
+    );
+    let radius = gal.radius.unwrap();
+    let thickness = gal.thickness.unwrap_or(0.);
+    let shape = gal.shape.unwrap();
+    let spawn = |nbody: u32| -> Vec<Particle3> {
+        match shape {
+            GalaxyShape::Spheroid(flattening) => spawn_spheroid(radius, flattening, nbody),
+            _ => physics::spawn_disk(shape, radius, nbody).iter().map(|p| {
+                // disk particles get a gaussian vertical profile
+                Particle3::new(Vec3 { x: p.pos.x, y: p.pos.y, z: thickness * gaussian() }, Vec3::zero(), p.mass)
+            }).collect()
+        }
+    };
+    let mut particles = spawn(gal.nbody);
+    for p in particles.iter_mut() {
+        p.kind = gal.species.unwrap_or(Species::Star);
+    }
+    for mut p in spawn(gal.tracers.unwrap_or(0)) {
+        p.kind = Species::Test;
+        particles.push(p);
+    }
+    let in_plane = !matches!(shape, GalaxyShape::Spheroid(_));
+    match gal.kinetics.unwrap() {
+        GalaxyKinetics::ZeroVel               => (),
+        GalaxyKinetics::RandomVel(minv, maxv) => for p in particles.iter_mut() {
+            p.vel = random_unit().scale(rand::random::<f64>() * (maxv - minv) + minv);
+        },
+        GalaxyKinetics::CircularOrbit         => init_circular_orbits(&mut particles, central_pcl.mass, in_plane)
+    };
+    let incl = gal.inclination.unwrap_or(0.).to_radians();
+    let mut central_pcl = central_pcl;
+    central_pcl.kind = gal.central_species.unwrap_or(Species::Star);
+    for p in particles.iter_mut() {
+        p.pos = p.pos.tilt(incl);
+        p.vel = p.vel.tilt(incl);
+        p.pos.add(&central_pcl.pos);
+        p.vel.add(&central_pcl.vel);
+    }
+    particles.push(central_pcl);
+    particles
+}
+
+#[cfg(test)]
+pub mod tests {
+    use rand;
+    use physics::tests::lock;
+    use super::{Particle3, Vec3, ForceSolver3, Classical3, stepsim};
+
+    //n particles with masses in [0.5, 1.5) scattered over a cube of side `side` at the origin
+    pub fn random_particles3(n: usize, side: f64) -> Vec<Particle3> {
+        let coord = || (rand::random::<f64>() - 0.5) * side;
+        (0..n).map(|i| {
+            let pos = Vec3 { x: coord(), y: coord(), z: coord() };
+            let mut p = Particle3::new(pos, Vec3::zero(), 0.5 + rand::random::<f64>());
+            p.id = i as u32;
+            p
+        }).collect()
+    }
+
+    pub fn forces_of3(solver: &mut dyn ForceSolver3, particles: &Vec<Particle3>) -> Vec<Vec3> {
+        let mut frcs = vec![Vec3::zero(); particles.len()];
+        solver.forces(particles, &mut frcs);
+        frcs
+    }
+
+    fn momentum(particles: &Vec<Particle3>) -> Vec3 {
+        let mut total = Vec3::zero();
+        for p in particles { total.add(&p.vel.scale(p.mass)) }
+        total
+    }
+
+    #[test]
+    fn stepsim_conserves_momentum() {
+        let _g = lock();
+        let mut particles = random_particles3(200, 100.);
+        for p in particles.iter_mut() {
+            p.vel = Vec3 { x: rand::random::<f64>() - 0.5, y: rand::random::<f64>() - 0.5, z: 0. };
+        }
+        let start = momentum(&particles);
+        let mut frcs = Vec::new();
+        for _ in 0..20 {
+            stepsim(&mut particles, &mut Classical3, &mut frcs);
+        }
+        let drift = momentum(&particles).diff(start).modulus();
+        assert!(drift < 1e-9, "momentum drifted by {}", drift);
+    }
+}
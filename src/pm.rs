@@ -0,0 +1,190 @@
+use physics::{Particle, PhysVec, ForceSolver};
+use barneshut::find_bounding_box;
+use complex::Complex;
+use config::MassAssignment;
+use fft;
+
+// Particle-mesh gravity. Masses are assigned to an n x n mesh spanning the particles,
+// the potential is found by convolving with the Green's function of the 2D force law
+// (G = ln r) using FFTs, and forces are differenced on the mesh and interpolated back
+// with the same assignment kernel so particles feel no self-force. The mesh is embedded
+// in a zero-padded 2n x 2n grid so the FFT's periodicity does not wrap the potential:
+// boundaries are open, like the other solvers.
+
+// cells kept clear around the particles so the assignment kernel and the force
+// differencing stencil never reach past the mesh
+pub static MARGIN: usize = 2;
+
+pub struct ParticleMesh {
+    n: usize,
+    assignment: MassAssignment,
+    grid: Vec<Complex>,        // 2n x 2n: mass, then potential
+    green: Vec<Complex>,       // transform of the Green's function, fixed for a given n
+    fx: Vec<f64>,              // n x n mesh accelerations
+    fy: Vec<f64>,
+    x0: f64,                   // position of mesh point (0, 0)
+    y0: f64,
+    cell: f64
+}
+
+impl ParticleMesh {
+    pub fn new(n: u32, assignment: MassAssignment) -> ParticleMesh {
+        let n = n as usize;
+        assert!(n.is_power_of_two() && n > 2 * MARGIN + 1, "pm_grid must be a power of two above {}", 2 * MARGIN + 1);
+        let m = 2 * n;
+        // mesh spacing is 1 here: a change of cell size only shifts ln r by a constant
+        let mut green = vec![Complex::zero(); m * m];
+        for y in 0..m {
+            for x in 0..m {
+                let dx = if x <= n { x } else { m - x } as f64;
+                let dy = if y <= n { y } else { m - y } as f64;
+                let r = (dx*dx + dy*dy).sqrt();
+                // the value at r = 0 cancels out of the differenced force
+                let g = if r > 0. { r.ln() } else { 0. };
+                green[y * m + x] = Complex::new(g, 0.);
+            }
+        }
+        fft::fft2(&mut green, m, false);
+        ParticleMesh { n: n, assignment: assignment, grid: vec![Complex::zero(); m * m], green: green,
+                       fx: vec![0.; n * n], fy: vec![0.; n * n], x0: 0., y0: 0., cell: 1. }
+    }
+
+    pub fn cell_size(&self) -> f64 {
+        self.cell
+    }
+
+    //multiply every mode of the Green's function by filter(k^2), k in inverse mesh cells
+    pub fn filter_green<F: Fn(f64) -> f64>(&mut self, filter: F) {
+        let m = 2 * self.n;
+        let dk = 2. * ::std::f64::consts::PI / m as f64;
+        for y in 0..m {
+            for x in 0..m {
+                let kx = if x <= m / 2 { x as f64 } else { x as f64 - m as f64 } * dk;
+                let ky = if y <= m / 2 { y as f64 } else { y as f64 - m as f64 } * dk;
+                let g = &mut self.green[y * m + x];
+                *g = g.scale(filter(kx*kx + ky*ky));
+            }
+        }
+    }
+
+    //fit the mesh over the particles, leaving MARGIN cells clear on every side
+    fn place_mesh(&mut self, particles: &Vec<Particle>) {
+        let (xmax, xmin, ymax, ymin) = find_bounding_box(particles);
+        let span = f64::max(f64::max(xmax - xmin, ymax - ymin), 1e-10);
+        self.cell = span / (self.n - 2 * MARGIN - 1) as f64;
+        self.x0 = xmin - MARGIN as f64 * self.cell;
+        self.y0 = ymin - MARGIN as f64 * self.cell;
+    }
+
+    //mesh points touched by p and their weights
+    fn stencil(&self, p: &Particle) -> ([(usize, usize, f64); 9], usize) {
+        let ux = (p.pos.x - self.x0) / self.cell;
+        let uy = (p.pos.y - self.y0) / self.cell;
+        let (sx, wx, nx) = weights(ux, self.assignment);
+        let (sy, wy, ny) = weights(uy, self.assignment);
+        let mut out = [(0, 0, 0.); 9];
+        let mut k = 0;
+        for j in 0..ny {
+            for i in 0..nx {
+                out[k] = (sx + i, sy + j, wx[i] * wy[j]);
+                k += 1;
+            }
+        }
+        (out, k)
+    }
+
+    //compute the mesh accelerations for the current particle positions
+    pub fn solve(&mut self, particles: &Vec<Particle>) {
+        let n = self.n;
+        let m = 2 * n;
+        self.place_mesh(particles);
+        for g in self.grid.iter_mut() {
+            *g = Complex::zero();
+        }
+        for p in particles {
+            let (pts, k) = self.stencil(p);
+            for &(x, y, w) in pts[..k].iter() {
+                self.grid[y * m + x].re += p.source_mass() * w;
+            }
+        }
+        fft::fft2(&mut self.grid, m, false);
+        for (g, h) in self.grid.iter_mut().zip(self.green.iter()) {
+            *g = *g * *h;
+        }
+        fft::fft2(&mut self.grid, m, true);
+        // a = -grad(phi), by central differences
+        let h2 = 2. * self.cell;
+        for y in 1..n - 1 {
+            for x in 1..n - 1 {
+                self.fx[y * n + x] = -(self.grid[y * m + x + 1].re - self.grid[y * m + x - 1].re) / h2;
+                self.fy[y * n + x] = -(self.grid[(y + 1) * m + x].re - self.grid[(y - 1) * m + x].re) / h2;
+            }
+        }
+    }
+
+    //mesh acceleration interpolated to p
+    pub fn accel(&self, p: &Particle) -> PhysVec {
+        let (pts, k) = self.stencil(p);
+        let mut a = PhysVec { x: 0., y: 0. };
+        for &(x, y, w) in pts[..k].iter() {
+            a.x += self.fx[y * self.n + x] * w;
+            a.y += self.fy[y * self.n + x] * w;
+        }
+        a
+    }
+}
+
+impl ForceSolver for ParticleMesh {
+    fn forces(&mut self, particles: &Vec<Particle>, frcs: &mut Vec<PhysVec>) {
+        if particles.len() == 0 { return }
+        self.solve(particles);
+        for (p, f) in particles.iter().zip(frcs.iter_mut()) {
+            let a = self.accel(p);
+            *f = PhysVec { x: a.x * p.mass, y: a.y * p.mass };
+        }
+    }
+}
+
+//first mesh index, weights and number of points along one axis, u in mesh units
+fn weights(u: f64, scheme: MassAssignment) -> (usize, [f64; 3], usize) {
+    match scheme {
+        MassAssignment::Ngp => ((u + 0.5) as usize, [1., 0., 0.], 1),
+        MassAssignment::Cic => {
+            let i = u as usize;
+            let f = u - i as f64;
+            (i, [1. - f, f, 0.], 2)
+        }
+        MassAssignment::Tsc => {
+            let i = (u + 0.5) as usize;
+            let d = u - i as f64;
+            (i - 1, [0.5*(0.5 - d)*(0.5 - d), 0.75 - d*d, 0.5*(0.5 + d)*(0.5 + d)], 3)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use physics::tests::{lock, random_particles, exact_forces, forces_of};
+    use config::MassAssignment;
+    use super::ParticleMesh;
+
+    //median of |f - exact| / |exact|
+    fn median_error(assignment: MassAssignment) -> f64 {
+        let particles = random_particles(1000, 100.);
+        let exact = exact_forces(&particles);
+        let frcs = forces_of(&mut ParticleMesh::new(256, assignment), &particles);
+        let mut errs: Vec<f64> = frcs.iter().zip(exact.iter()).map(|(f, e)| f.diff(*e).modulus() / e.modulus()).collect();
+        errs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        errs[errs.len() / 2]
+    }
+
+    #[test]
+    fn matches_classical() {
+        let _g = lock();
+        // nearest grid point is much the noisiest
+        for &(a, bound) in [(MassAssignment::Ngp, 0.03), (MassAssignment::Cic, 0.01), (MassAssignment::Tsc, 0.01)].iter() {
+            let err = median_error(a);
+            assert!(err < bound, "{:?} median error {}", a, err);
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use std::sync::{Arc, mpsc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::panic::{self, AssertUnwindSafe};
+use std::any::Any;
+use std::{cmp, mem, slice, thread};
+
+// A fixed set of worker threads, started once and kept for the whole run. `run` splits
+// a range into chunks which the workers (and the calling thread) claim until none are
+// left, and only returns once every worker has let go of the job, whether it finished
+// or panicked. That is what makes it safe to hand the workers a task that borrows from
+// the caller's stack. A panic in any task is passed on to the caller of `run`.
+pub struct ThreadPool {
+    senders: Vec<mpsc::Sender<Arc<Job>>>,
+    done: mpsc::Receiver<Option<Box<dyn Any + Send>>>
+}
+
+struct Job {
+    task: *const (dyn Fn(usize, usize) + Sync),    // only valid until `run` returns
+    len: usize,
+    chunk: usize,
+    next: AtomicUsize
+}
+
+unsafe impl Send for Job {}
+unsafe impl Sync for Job {}
+
+impl Job {
+    fn work(&self) {
+        loop {
+            let start = self.next.fetch_add(self.chunk, Ordering::SeqCst);
+            if start >= self.len { break }
+            let end = cmp::min(start + self.chunk, self.len);
+            unsafe { (*self.task)(start, end) }
+        }
+    }
+}
+
+impl ThreadPool {
+    pub fn new(nthreads: usize) -> ThreadPool {
+        let (donetx, donerx) = mpsc::channel();
+        let mut senders = Vec::new();
+        // the thread calling `run` does its share, so spawn one fewer
+        for _ in 1..nthreads {
+            let (tx, rx) = mpsc::channel::<Arc<Job>>();
+            let localdone = donetx.clone();
+            thread::spawn(move || {
+                for job in rx.iter() {
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| job.work()));
+                    drop(job);
+                    localdone.send(result.err()).unwrap();
+                }
+            });
+            senders.push(tx);
+        }
+        ThreadPool { senders: senders, done: donerx }
+    }
+
+    pub fn size(&self) -> usize {
+        self.senders.len() + 1
+    }
+
+    //call task(start, end) for consecutive chunks covering 0..len
+    pub fn run<F: Fn(usize, usize) + Sync>(&self, len: usize, chunk: usize, task: &F) {
+        let task: &(dyn Fn(usize, usize) + Sync) = task;
+        let job = Arc::new(Job {
+            task: unsafe { mem::transmute(task) },
+            len: len,
+            chunk: cmp::max(chunk, 1),
+            next: AtomicUsize::new(0)
+        });
+        let mut wait = Wait { done: &self.done, pending: 0, panic: None };
+        for tx in &self.senders {
+            tx.send(job.clone()).unwrap();
+            wait.pending += 1;
+        }
+        let mine = panic::catch_unwind(AssertUnwindSafe(|| job.work()));
+        wait.all();
+        match mine {
+            Err(e) => panic::resume_unwind(e),
+            Ok(()) => ()
+        }
+        match wait.panic.take() {
+            Some(e) => panic::resume_unwind(e),
+            None    => ()
+        }
+    }
+
+    //call task(start, chunk) for consecutive disjoint chunks of data, start being the
+    //offset of the chunk in data
+    pub fn run_mut<T: Send, F: Fn(usize, &mut [T]) + Sync>(&self, data: &mut [T], chunk: usize, task: &F) {
+        let base = SendPtr(data.as_mut_ptr());
+        let base = &base;
+        self.run(data.len(), chunk, &|start, end| {
+            // run hands out each chunk exactly once, so these never overlap
+            let part = unsafe { slice::from_raw_parts_mut(base.0.add(start), end - start) };
+            task(start, part)
+        });
+    }
+
+    //chunk size giving each thread several chunks to balance uneven work
+    pub fn chunk_for(&self, len: usize) -> usize {
+        cmp::max(len / (8 * self.size()), 1)
+    }
+}
+
+// The slice behind run_mut, shared with the workers. Only run_mut makes these, and it
+// gives each task a chunk no other task sees.
+struct SendPtr<T>(*mut T);
+
+unsafe impl<T: Send> Sync for SendPtr<T> {}
+
+// Collects the workers' replies to a job. It also waits for them when dropped, so even a
+// panic unwinding out of `run` can't free the task while a worker is still using it.
+struct Wait<'a> {
+    done: &'a mpsc::Receiver<Option<Box<dyn Any + Send>>>,
+    pending: usize,
+    panic: Option<Box<dyn Any + Send>>      // the first worker panic
+}
+
+impl<'a> Wait<'a> {
+    fn all(&mut self) {
+        while self.pending > 0 {
+            self.pending -= 1;
+            match self.done.recv() {
+                Ok(Some(e)) => if self.panic.is_none() { self.panic = Some(e) },
+                Ok(None)    => (),
+                // every worker is gone, so none can be holding the job
+                Err(_)      => self.pending = 0
+            }
+        }
+    }
+}
+
+impl<'a> Drop for Wait<'a> {
+    fn drop(&mut self) {
+        self.all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::ThreadPool;
+
+    #[test]
+    fn run_mut_covers_every_element_once() {
+        let pool = ThreadPool::new(4);
+        let mut data = vec![0; 1000];
+        pool.run_mut(&mut data, 7, &|start, chunk: &mut [usize]| {
+            for (i, x) in chunk.iter_mut().enumerate() {
+                *x += start + i;
+            }
+        });
+        assert!(data.iter().enumerate().all(|(i, &x)| x == i));
+    }
+
+    #[test]
+    fn worker_panic_reaches_caller() {
+        let pool = ThreadPool::new(4);
+        let count = AtomicUsize::new(0);
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            pool.run(100, 1, &|start, _| {
+                count.fetch_add(1, Ordering::SeqCst);
+                if start == 50 { panic!("task failed") }
+            });
+        }));
+        assert!(result.is_err());
+        // every chunk was still claimed, and the pool is still usable afterwards
+        assert!(count.load(Ordering::SeqCst) == 100);
+        let after = AtomicUsize::new(0);
+        pool.run(10, 1, &|_, _| { after.fetch_add(1, Ordering::SeqCst); });
+        assert!(after.load(Ordering::SeqCst) == 10);
+    }
+}
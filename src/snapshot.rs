@@ -0,0 +1,43 @@
+use physics::Particle;
+use physics3d::Particle3;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+// Plain text snapshot, one particle per line. Particles get reordered in memory during
+// a run, so lines are written in id order to keep snapshots from different runs comparable.
+// The simulation time goes in the header.
+pub fn write_snapshot(path: &str, particles: &Vec<Particle>, time: f64) {
+    let mut order: Vec<&Particle> = particles.iter().collect();
+    order.sort_by_key(|p| p.id);
+    let mut out = BufWriter::new(File::create(Path::new(path)).unwrap());
+    writeln!(out, "# time {}", time).unwrap();
+    writeln!(out, "# id species origin posx posy velx vely mass").unwrap();
+    for p in order {
+        writeln!(out, "{} {:?} {} {} {} {} {} {}", p.id, p.kind, p.origin,
+                 p.pos.x, p.pos.y, p.vel.x, p.vel.y, p.mass).unwrap();
+    }
+}
+
+//per particle errors from an accuracy check, in id order like the snapshots
+pub fn write_errors(path: &str, particles: &Vec<Particle>, errors: &Vec<f64>) {
+    let mut order: Vec<(&Particle, f64)> = particles.iter().zip(errors.iter().cloned()).collect();
+    order.sort_by_key(|p| p.0.id);
+    let mut out = BufWriter::new(File::create(Path::new(path)).unwrap());
+    writeln!(out, "# id error").unwrap();
+    for (p, e) in order {
+        writeln!(out, "{} {}", p.id, e).unwrap();
+    }
+}
+
+pub fn write_snapshot3(path: &str, particles: &Vec<Particle3>, time: f64) {
+    let mut order: Vec<&Particle3> = particles.iter().collect();
+    order.sort_by_key(|p| p.id);
+    let mut out = BufWriter::new(File::create(Path::new(path)).unwrap());
+    writeln!(out, "# time {}", time).unwrap();
+    writeln!(out, "# id species origin posx posy posz velx vely velz mass").unwrap();
+    for p in order {
+        writeln!(out, "{} {:?} {} {} {} {} {} {} {} {}", p.id, p.kind, p.origin, p.pos.x, p.pos.y, p.pos.z,
+                 p.vel.x, p.vel.y, p.vel.z, p.mass).unwrap();
+    }
+}
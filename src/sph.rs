@@ -0,0 +1,179 @@
+use barneshut::QuadTree;
+use config::{Eos, Species};
+use physics::{Particle, PhysVec};
+use std::f64;
+
+// Smoothed particle hydrodynamics for the gas particles. Densities are gathered over
+// each particle's own smoothing length, which is adjusted every step towards a target
+// number of neighbours once the step's forces are done, so the pairs found within the
+// gathering lengths are exactly those the kernels reach. Pressure forces and Monaghan
+// artificial viscosity are then summed once per interacting pair with the kernel
+// gradients of both particles averaged, so momentum is conserved exactly. The hydro
+// kick is applied on its own, before the gravity step, which lets any gravity solver
+// be used for the whole population.
+
+// 2D cubic spline normalisation, 10/(7 pi)
+static SIGMA : f64 = 0.454728408833987;
+
+pub struct Sph {
+    tree: QuadTree,
+    near: Vec<usize>,
+    pairs: Vec<(usize, usize)>,
+    counts: Vec<u32>,        // neighbours each particle found this step
+    eos: Eos,
+    gamma: f64,
+    sound_speed: f64,        // isothermal only
+    neighbours: f64,         // target neighbour count
+    h_init: f64,             // smoothing length of gas not yet seen
+    alpha: f64,
+    beta: f64
+}
+
+impl Sph {
+    pub fn new(eos: Eos, gamma: f64, sound_speed: f64, neighbours: u32, h_init: f64, alpha: f64, beta: f64) -> Sph {
+        Sph { tree: QuadTree::new(&Vec::new()), near: Vec::new(), pairs: Vec::new(), counts: Vec::new(), eos: eos, gamma: gamma,
+              sound_speed: sound_speed, neighbours: neighbours as f64, h_init: h_init, alpha: alpha, beta: beta }
+    }
+
+    fn pressure(&self, p: &Particle) -> f64 {
+        match self.eos {
+            Eos::Adiabatic  => (self.gamma - 1.) * p.rho * p.u,
+            Eos::Isothermal => self.sound_speed * self.sound_speed * p.rho
+        }
+    }
+
+    fn sound(&self, p: &Particle, pressure: f64) -> f64 {
+        match self.eos {
+            Eos::Adiabatic  => (self.gamma * pressure / p.rho).sqrt(),
+            Eos::Isothermal => self.sound_speed
+        }
+    }
+
+    //update gas densities, kick gas velocities and energies by dt, then update smoothing lengths
+    pub fn kick(&mut self, particles: &mut Vec<Particle>, dt: f64) {
+        self.tree.build(particles);
+        self.pairs.clear();
+        self.counts.clear();
+        self.counts.resize(particles.len(), 0);
+        for i in 0..particles.len() {
+            if particles[i].kind != Species::Gas { continue }
+            if particles[i].h == 0. { particles[i].h = self.h_init }
+            self.near.clear();
+            let (pos, h) = (particles[i].pos, particles[i].h);
+            self.tree.neighbours(particles, &pos, 2. * h, &mut self.near);
+            let mut rho = 0.;
+            let mut count = 0;
+            for &j in &self.near {
+                let q = &particles[j];
+                if q.kind != Species::Gas { continue }
+                rho += q.mass * kernel(pos.separation(q.pos).modulus(), h);
+                count += 1;
+                if j != i { self.pairs.push(if i < j { (i, j) } else { (j, i) }) }
+            }
+            particles[i].rho = rho;
+            self.counts[i] = count;
+        }
+        self.pairs.sort();
+        self.pairs.dedup();
+
+        let n = particles.len();
+        let mut dv = vec![PhysVec { x: 0., y: 0. }; n];
+        let mut du = vec![0.; n];
+        let press: Vec<f64> = particles.iter().map(|p| if p.kind == Species::Gas { self.pressure(p) } else { 0. }).collect();
+        for &(i, j) in &self.pairs {
+            let (a, b) = (&particles[i], &particles[j]);
+            let rij = b.pos.separation(a.pos);        // from j to i
+            let r = rij.modulus();
+            if r == 0. { continue }
+            let grad = 0.5 * (kernel_deriv(r, a.h) + kernel_deriv(r, b.h)) / r;
+            let vij = PhysVec { x: a.vel.x - b.vel.x, y: a.vel.y - b.vel.y };
+            let vr = vij.dot(&rij);
+            let visc = if vr < 0. {
+                let h = 0.5 * (a.h + b.h);
+                let mu = h * vr / (r * r + 0.01 * h * h);
+                let c = 0.5 * (self.sound(a, press[i]) + self.sound(b, press[j]));
+                (-self.alpha * c * mu + self.beta * mu * mu) / (0.5 * (a.rho + b.rho))
+            } else { 0. };
+            let term = press[i] / (a.rho * a.rho) + press[j] / (b.rho * b.rho) + visc;
+            // grad_i W = grad * rij, grad_j W = -grad * rij
+            dv[i].x -= b.mass * term * grad * rij.x;
+            dv[i].y -= b.mass * term * grad * rij.y;
+            dv[j].x += a.mass * term * grad * rij.x;
+            dv[j].y += a.mass * term * grad * rij.y;
+            du[i] += 0.5 * b.mass * term * grad * vr;
+            du[j] += 0.5 * a.mass * term * grad * vr;
+        }
+        for (((p, v), &e), &count) in particles.iter_mut().zip(dv.iter()).zip(du.iter()).zip(self.counts.iter()) {
+            if p.kind != Species::Gas { continue }
+            p.vel.x += v.x * dt;
+            p.vel.y += v.y * dt;
+            match self.eos {
+                Eos::Adiabatic  => p.u = f64::max(p.u + e * dt, 0.),
+                Eos::Isothermal => ()
+            }
+            // move halfway towards the length that would give the target count (area ~ h^2)
+            p.h *= 0.5 * (1. + (self.neighbours / count as f64).sqrt());
+        }
+    }
+}
+
+//cubic spline kernel with support 2h
+fn kernel(r: f64, h: f64) -> f64 {
+    let q = r / h;
+    let norm = SIGMA / (h * h);
+    if q < 1. {
+        norm * (1. - 1.5 * q * q + 0.75 * q * q * q)
+    } else if q < 2. {
+        norm * 0.25 * (2. - q) * (2. - q) * (2. - q)
+    } else {
+        0.
+    }
+}
+
+//dW/dr
+fn kernel_deriv(r: f64, h: f64) -> f64 {
+    let q = r / h;
+    let norm = SIGMA / (h * h * h);
+    if q < 1. {
+        norm * (-3. * q + 2.25 * q * q)
+    } else if q < 2. {
+        -norm * 0.75 * (2. - q) * (2. - q)
+    } else {
+        0.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::{Eos, Species};
+    use physics::tests::{lock, random_particles};
+    use super::{Sph, kernel_deriv};
+
+    #[test]
+    fn forces_use_the_lengths_pairs_were_found_with() {
+        let _g = lock();
+        let mut particles = random_particles(300, 60.);
+        for p in particles.iter_mut() { p.kind = Species::Gas }
+        // isothermal and inviscid, so the acceleration is just the pressure gradient
+        let mut sph = Sph::new(Eos::Isothermal, 1., 1., 32, 2., 0., 0.);
+        // the first kick leaves the lengths far from their targets, so the second has mixed ones
+        sph.kick(&mut particles, 1.);
+        for p in particles.iter_mut() { p.vel.x = 0.; p.vel.y = 0. }
+        let h: Vec<f64> = particles.iter().map(|p| p.h).collect();
+        sph.kick(&mut particles, 1.);
+        for (i, a) in particles.iter().enumerate() {
+            let (mut ax, mut ay) = (0., 0.);
+            for (j, b) in particles.iter().enumerate() {
+                let rij = b.pos.separation(a.pos);
+                let r = rij.modulus();
+                if j == i || r == 0. { continue }
+                let grad = 0.5 * (kernel_deriv(r, h[i]) + kernel_deriv(r, h[j])) / r;
+                let term = 1. / a.rho + 1. / b.rho;
+                ax -= b.mass * term * grad * rij.x;
+                ay -= b.mass * term * grad * rij.y;
+            }
+            let err = ((a.vel.x - ax).powi(2) + (a.vel.y - ay).powi(2)).sqrt();
+            assert!(err <= 1e-9 * (ax * ax + ay * ay).sqrt() + 1e-12, "particle {} off by {}", i, err);
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use physics::{Particle, PhysVec, ForceSolver, DT};
+use std::f64;
+
+// Block timesteps: each particle moves with its own step dt/2^level, chosen from its
+// acceleration as eta*sqrt(eps/|a|). One call advances every particle by dt in 2^max_level
+// substeps; at each substep only the particles starting a new step of their own have their
+// forces evaluated and are kicked, while everyone drifts. Levels live on the particles so
+// they survive reordering.
+
+pub struct BlockParams {
+    pub max_level: u32,
+    pub eta: f64,
+    pub eps: f64
+}
+
+//level whose step is the longest one not exceeding eta*sqrt(eps/|a|)
+fn wanted_level(acc: &PhysVec, params: &BlockParams) -> u32 {
+    let a = acc.modulus();
+    if a == 0. { return 0 }
+    let want = params.eta * (params.eps / a).sqrt();
+    let dt = unsafe { DT };
+    let level = (dt / want).log2().ceil();
+    if level <= 0. { 0 } else if level >= params.max_level as f64 { params.max_level } else { level as u32 }
+}
+
+//global step for the coming frame: the criterion above applied to the largest acceleration
+//of the last step, within [dt_min, dt_max]. Before any accelerations are known, dt_min
+pub fn adaptive_dt(amax: f64, eta: f64, eps: f64, dt_min: f64, dt_max: f64) -> f64 {
+    if amax == 0. { return dt_min }
+    f64::min(f64::max(eta * (eps / amax).sqrt(), dt_min), dt_max)
+}
+
+//frcs and active are scratch space kept by the caller
+pub fn stepsim_block(particles: &mut Vec<Particle>, solver: &mut dyn ForceSolver, params: &BlockParams,
+                     frcs: &mut Vec<PhysVec>, active: &mut Vec<bool>) {
+    let max = params.max_level;
+    let nsub: u64 = 1 << max;
+    let dt = unsafe { DT };
+    let dtmin = dt / nsub as f64;
+    frcs.resize(particles.len(), PhysVec {x: 0., y: 0.});
+    active.resize(particles.len(), false);
+    for s in 0..nsub {
+        let mut any = false;
+        for (p, a) in particles.iter_mut().zip(active.iter_mut()) {
+            // levels may come from an earlier run with more of them
+            if p.level > max { p.level = max }
+            *a = s % (1 << (max - p.level)) == 0;
+            any = any || *a;
+        }
+        if any {
+            solver.forces_active(particles, active, frcs);
+            for ((p, &f), &a) in particles.iter_mut().zip(frcs.iter()).zip(active.iter()) {
+                if !a { continue }
+                p.acc = PhysVec { x: f.x/p.mass, y: f.y/p.mass };
+                // shorter steps can start anywhere, longer ones only on their own boundaries
+                let mut level = wanted_level(&p.acc, params);
+                while level < p.level && s % (1 << (max - level)) != 0 {
+                    level += 1;
+                }
+                p.level = level;
+                let step = dt / (1u64 << level) as f64;
+                p.vel.x += p.acc.x * step;
+                p.vel.y += p.acc.y * step;
+            }
+        }
+        for p in particles.iter_mut() {
+            p.pos.x += p.vel.x * dtmin;
+            p.pos.y += p.vel.y * dtmin;
+            p.pos.wrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use physics::{self, Particle, PhysVec, ForceSolver, Classical, DT};
+    use physics::tests::{lock, random_particles};
+    use super::{BlockParams, stepsim_block, wanted_level, adaptive_dt};
+
+    // a fixed force on each particle, recording which were asked for
+    struct Fixed {
+        frcs: Vec<PhysVec>,
+        calls: Vec<Vec<bool>>
+    }
+
+    impl ForceSolver for Fixed {
+        fn forces(&mut self, _: &Vec<Particle>, frcs: &mut Vec<PhysVec>) {
+            frcs.clone_from(&self.frcs);
+        }
+
+        fn forces_active(&mut self, particles: &Vec<Particle>, active: &Vec<bool>, frcs: &mut Vec<PhysVec>) {
+            self.calls.push(active.clone());
+            self.forces(particles, frcs)
+        }
+    }
+
+    fn params() -> BlockParams {
+        BlockParams { max_level: 6, eta: 0.2, eps: 1. }
+    }
+
+    #[test]
+    fn level_from_acceleration() {
+        let _g = lock();
+        let params = params();
+        let dt = unsafe { DT };
+        assert_eq!(wanted_level(&PhysVec { x: 0., y: 0. }, &params), 0);
+        assert_eq!(wanted_level(&PhysVec { x: 1e-6, y: 0. }, &params), 0);
+        assert_eq!(wanted_level(&PhysVec { x: 1e12, y: 0. }, &params), params.max_level);
+        for &a in &[20., 100., 500., 2000.] {
+            let level = wanted_level(&PhysVec { x: 0., y: a }, &params);
+            let want = params.eta * (params.eps / a).sqrt();
+            // the longest step that isn't too long
+            assert!(dt / (1u64 << level) as f64 <= want, "level {} too coarse for a = {}", level, a);
+            assert!(level == 0 || dt / (1u64 << (level - 1)) as f64 > want, "level {} too fine for a = {}", level, a);
+        }
+    }
+
+    #[test]
+    fn adaptive_dt_follows_encounter() {
+        let _g = lock();
+        let (eta, eps, dt_min, dt_max) = (0.2, 0.01, 1e-3, 0.1);
+        // two bodies falling together from rest; the step shrinks as they close
+        let mut particles = vec![Particle::new(PhysVec { x: -5., y: 0. }, PhysVec { x: 0., y: 0. }, 1.),
+                                 Particle::new(PhysVec { x: 5., y: 0. }, PhysVec { x: 0., y: 0. }, 1.)];
+        assert_eq!(adaptive_dt(0., eta, eps, dt_min, dt_max), dt_min);
+        let mut frcs = Vec::new();
+        let mut last = dt_max;
+        let mut steps = Vec::new();
+        while particles[1].pos.x - particles[0].pos.x > 0.05 {
+            let amax = particles.iter().fold(0., |a: f64, p| a.max(p.acc.modulus()));
+            let dt = adaptive_dt(amax, eta, eps, dt_min, dt_max);
+            assert!(dt >= dt_min && dt <= dt_max, "step {} out of bounds", dt);
+            if amax > 0. {
+                assert!(dt <= last, "step grew from {} to {} on the way in", last, dt);
+                last = dt;
+            }
+            unsafe { DT = dt };
+            physics::stepsim(&mut particles, &mut Classical, &mut frcs);
+            steps.push(dt);
+        }
+        assert_eq!(steps[0], dt_min);
+        assert!(steps[1] > 2. * steps[steps.len() - 1], "steps {:?}", steps);
+        // however close, never below dt_min, and however far, never above dt_max
+        assert_eq!(adaptive_dt(1e12, eta, eps, dt_min, dt_max), dt_min);
+        assert_eq!(adaptive_dt(1e-12, eta, eps, dt_min, dt_max), dt_max);
+    }
+
+    #[test]
+    fn block_resyncs() {
+        let _g = lock();
+        let params = params();
+        let dt = unsafe { DT };
+        // accelerations wanting every level from 0 to beyond the deepest
+        let accs = [0., 1., 30., 150., 600., 2500., 1e4, 1e6];
+        let mut particles: Vec<Particle> = accs.iter().map(|_| {
+            Particle::new(PhysVec { x: 0., y: 0. }, PhysVec { x: 0., y: 0. }, 2.)
+        }).collect();
+        let mut solver = Fixed { frcs: accs.iter().map(|&a| PhysVec { x: 2. * a, y: 0. }).collect(), calls: Vec::new() };
+        let (mut frcs, mut active) = (Vec::new(), Vec::new());
+        for block in 1..3 {
+            let start = solver.calls.len();
+            stepsim_block(&mut particles, &mut solver, &params, &mut frcs, &mut active);
+            // everyone starts the block together
+            assert!(solver.calls[start].iter().all(|&a| a));
+            for (p, &a) in particles.iter().zip(accs.iter()) {
+                assert_eq!(p.level, wanted_level(&PhysVec { x: a, y: 0. }, &params));
+                // and the steps taken fill the block exactly, so it ends together too
+                let kicked = p.vel.x / a;
+                assert!(a == 0. || (kicked - block as f64 * dt).abs() < 1e-12 * dt, "kicked for {} of {}", kicked, dt);
+            }
+            // each level is evaluated once per step of its own
+            for (i, p) in particles.iter().enumerate() {
+                let evaluated = solver.calls[start..].iter().filter(|c| c[i]).count();
+                assert_eq!(evaluated, 1 << p.level);
+            }
+        }
+    }
+
+    #[test]
+    fn conserves_momentum_across_levels() {
+        let _g = lock();
+        // a heavy mass in the middle spreads the particles over several levels
+        let params = BlockParams { max_level: 6, eta: 0.2, eps: 1e-3 };
+        let mut particles = random_particles(100, 100.);
+        particles[0].pos = PhysVec { x: 0., y: 0. };
+        particles[0].mass = 100.;
+        // kept far enough out that the deepest level resolves it
+        for p in particles.iter_mut().skip(1) {
+            let r = p.pos.modulus();
+            if r < 10. { p.pos = PhysVec { x: p.pos.x * 10. / r, y: p.pos.y * 10. / r } }
+        }
+        let momentum = |ps: &Vec<Particle>| ps.iter().fold((0., 0.), |(x, y), p| (x + p.mass * p.vel.x, y + p.mass * p.vel.y));
+        let (mut frcs, mut active) = (Vec::new(), Vec::new());
+        let mut scale = 0.;
+        for _ in 0..5 {
+            stepsim_block(&mut particles, &mut Classical, &params, &mut frcs, &mut active);
+            scale = particles.iter().fold(0., |s, p| s + p.mass * p.vel.modulus());
+        }
+        let levels: Vec<u32> = particles.iter().map(|p| p.level).collect();
+        let (lo, hi) = (levels.iter().min().unwrap(), levels.iter().max().unwrap());
+        assert!(hi - lo >= 2, "levels {:?}", levels);
+        // kicks on different levels don't pair up exactly, so a little drift is allowed
+        let (px, py) = momentum(&particles);
+        let drift = (px * px + py * py).sqrt();
+        assert!(drift < 2e-3 * scale, "momentum {} against {}", drift, scale);
+    }
+}
@@ -0,0 +1,66 @@
+use physics::{Particle, PhysVec, ForceSolver};
+use barneshut::QuadTree;
+use pm::ParticleMesh;
+use pool::ThreadPool;
+use config::MassAssignment;
+use std::rc::Rc;
+
+// TreePM: long range forces come from a particle mesh whose Green's function is
+// smoothed on a scale of `split` mesh cells, short range forces from a quadtree walk
+// that only looks a few split scales around each particle. Dense galaxy cores get tree
+// accuracy, while the walk stays bounded however large the system is.
+pub struct TreePm {
+    mesh: ParticleMesh,
+    tree: QuadTree,
+    split: f64,
+    pool: Rc<ThreadPool>
+}
+
+impl TreePm {
+    pub fn new(grid: u32, assignment: MassAssignment, split: f64, pool: Rc<ThreadPool>) -> TreePm {
+        let mut mesh = ParticleMesh::new(grid, assignment);
+        mesh.filter_green(|k2| (-k2 * split * split).exp());
+        TreePm { mesh: mesh, tree: QuadTree::new(&Vec::new()), split: split, pool: pool }
+    }
+}
+
+impl ForceSolver for TreePm {
+    fn forces(&mut self, particles: &Vec<Particle>, frcs: &mut Vec<PhysVec>) {
+        if particles.len() == 0 { return }
+        self.mesh.solve(particles);
+        self.tree.build_par(particles, &self.pool);
+        let rs = self.split * self.mesh.cell_size();
+        let (mesh, tree) = (&self.mesh, &self.tree);
+        self.pool.run_mut(frcs, self.pool.chunk_for(particles.len()), &|start, out: &mut [PhysVec]| {
+            for (f, p) in out.iter_mut().zip(particles[start..].iter()) {
+                let a = mesh.accel(p);
+                *f = tree.force_short(particles, p, rs);
+                f.x += a.x * p.mass;
+                f.y += a.y * p.mass;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use physics::tests::{lock, random_particles, exact_forces, forces_of};
+    use config::MassAssignment;
+    use pool::ThreadPool;
+    use super::TreePm;
+
+    #[test]
+    fn matches_classical() {
+        let _g = lock();
+        let particles = random_particles(1000, 100.);
+        let exact = exact_forces(&particles);
+        let mut solver = TreePm::new(128, MassAssignment::Cic, 1.25, Rc::new(ThreadPool::new(4)));
+        let frcs = forces_of(&mut solver, &particles);
+        let mut errs: Vec<f64> = frcs.iter().zip(exact.iter()).map(|(f, e)| f.diff(*e).modulus() / e.modulus()).collect();
+        errs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        // the mesh half dominates: a few close pairs straddling the split do worst
+        let (median, worst) = (errs[errs.len() / 2], errs[errs.len() * 99 / 100]);
+        assert!(median < 0.015 && worst < 0.15, "median error {}, 99th percentile {}", median, worst);
+    }
+}
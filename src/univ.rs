@@ -1,4 +1,6 @@
-#![feature(core, collections)]
+// The code keeps its own idioms: `Foo { x: x }`, `&Vec<T>` parameters and one armed matches
+#![allow(clippy::redundant_field_names, clippy::ptr_arg, clippy::single_match, clippy::len_zero,
+         clippy::needless_range_loop, clippy::too_many_arguments)]
 
 extern crate rustc_serialize;
 extern crate sdl2;
@@ -6,65 +8,427 @@ extern crate time;
 extern crate getopts;
 extern crate toml;
 extern crate rand;
-extern crate deque;
+extern crate num_cpus;
 
 use sdl2::rect::Point;
-use physics::Particle;
+use physics::{Particle, PhysVec, ForceSolver};
+use physics3d::{Particle3, Vec3, ForceSolver3};
+use pool::ThreadPool;
 use config::{Display, Config, ConfigOpt};
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, Read, Write};
 use std::path::Path;
+use std::rc::Rc;
 
 mod physics;
 mod barneshut;
 mod config;
+mod morton;
+mod snapshot;
+mod pool;
+mod direct;
+mod fmm;
+mod complex;
+mod fft;
+mod pm;
+mod treepm;
+mod physics3d;
+mod octree;
+mod timestep;
+mod collide;
+mod external;
+mod cosmology;
+mod zeldovich;
+mod sph;
+mod blackhole;
+mod escape;
 
 
-fn pcls2points(particles: &Vec<Particle>, display: Display) -> Vec<Point> {
+// drawing colour of each species, in the order they are declared
+static COLOURS : [(u8, u8, u8); 5] = [
+    (255, 255, 255),    // star
+    (80, 160, 255),     // gas
+    (120, 80, 40),      // dark matter
+    (255, 60, 60),      // black hole
+    (90, 200, 90)       // test particle
+];
+
+//screen points, one list per species
+fn pcls2points(particles: &Vec<Particle>, display: Display) -> Vec<Vec<Point>> {
+    let midx = (display.width/2) as f64;
+    let midy = (display.height/2) as f64;
+    let mut arr: Vec<Vec<Point>> = vec![Vec::new(); COLOURS.len()];
+    for p in particles.iter() {
+        arr[p.kind as usize].push(Point::new((p.pos.x + midx) as i32, (p.pos.y + midy) as i32))
+    }
+    arr
+}
+
+//tip the system by the display tilt and drop the depth axis
+fn project(particles: &Vec<Particle3>, display: Display) -> Vec<Vec<Point>> {
     let midx = (display.width/2) as f64;
     let midy = (display.height/2) as f64;
-    let mut arr: Vec<Point> = Vec::new();
+    let tilt = display.tilt.to_radians();
+    let mut arr: Vec<Vec<Point>> = vec![Vec::new(); COLOURS.len()];
     for p in particles.iter() {
-        arr.push(Point {x: (p.pos.x + midx) as i32, y: (p.pos.y + midy) as i32 })
+        let v = p.pos.tilt(tilt);
+        arr[p.kind as usize].push(Point::new((v.x + midx) as i32, (v.y + midy) as i32))
     }
     arr
 }
 
-fn init_particles(cfg: &Config) ->  (Vec<Particle>, fn(&mut Vec<Particle>)) {
+//background potentials from the config; centres holds the id of each galaxy's central particle
+fn potentials(cfg: &Config, centres: &Vec<u32>) -> Vec<external::Potential> {
+    match cfg.potentials {
+        Some(ref pots) => pots.iter().map(|pot| {
+            external::Potential::new(pot, pot.galaxy.map(|g| centres[g]))
+        }).collect(),
+        None => Vec::new()
+    }
+}
+
+fn init_particles(cfg: &Config, pool: Rc<ThreadPool>) ->  (Vec<Particle>, Box<dyn ForceSolver>) {
+    let (particles, centres) = make_particles(cfg);
+    let solver: Box<dyn ForceSolver> = match cfg.sim {
+        config::SimType::BarnesHut => Box::new(barneshut::BarnesHut::new()),
+        config::SimType::BarnesHutParallel => Box::new(barneshut::BarnesHutParallel::new(pool)),
+        config::SimType::Classical => Box::new(physics::Classical),
+        config::SimType::ClassicalParallel => Box::new(direct::ClassicalParallel::new(pool)),
+        config::SimType::Fmm => Box::new(fmm::Fmm::new(cfg.fmm_order)),
+        config::SimType::ParticleMesh => Box::new(pm::ParticleMesh::new(cfg.pm_grid, cfg.pm_assignment)),
+        config::SimType::TreePm => Box::new(treepm::TreePm::new(cfg.pm_grid, cfg.pm_assignment, cfg.pm_split, pool)),
+    };
+    let solver = with_potentials(cfg, &centres, solver);
+    (particles, solver)
+}
+
+//the configured galaxies and fields, with the index of each galaxy's central body
+fn make_particles(cfg: &Config) -> (Vec<Particle>, Vec<u32>) {
     let mut particles : Vec<Particle> = Vec::new();
-    for gal in &cfg.galaxies {
-        let galaxy = physics::make_galaxy(gal.clone());
-        particles.push_all(&galaxy);
+    let mut centres = Vec::new();
+    for (origin, gal) in cfg.galaxies.iter().enumerate() {
+        let mut galaxy = physics::make_galaxy(gal.clone());
+        for p in galaxy.iter_mut() { p.origin = origin as u32 }
+        particles.extend_from_slice(&galaxy);
+        centres.push(particles.len() as u32 - 1);
+    };
+    match cfg.fields {
+        // fields are numbered on from the galaxies
+        Some(ref fields) => for (ix, field) in fields.iter().enumerate() {
+            let mut pcls = zeldovich::make_field(field);
+            for p in pcls.iter_mut() { p.origin = (cfg.galaxies.len() + ix) as u32 }
+            particles.extend_from_slice(&pcls);
+        },
+        None => ()
+    }
+    for (ix, p) in particles.iter_mut().enumerate() {
+        p.id = ix as u32;
+        p.pos.wrap();
+    }
+    (particles, centres)
+}
+
+//wrap solver in the configured external potentials, if there are any
+fn with_potentials(cfg: &Config, centres: &Vec<u32>, solver: Box<dyn ForceSolver>) -> Box<dyn ForceSolver> {
+    let pots = potentials(cfg, centres);
+    if pots.len() == 0 {
+        solver
+    } else {
+        Box::new(external::External::new(solver, pots))
+    }
+}
+
+fn init_particles3(cfg: &Config, pool: Rc<ThreadPool>) -> (Vec<Particle3>, Box<dyn ForceSolver3>) {
+    let mut particles : Vec<Particle3> = Vec::new();
+    let mut centres = Vec::new();
+    for (origin, gal) in cfg.galaxies.iter().enumerate() {
+        let mut galaxy = physics3d::make_galaxy(gal.clone());
+        for p in galaxy.iter_mut() { p.origin = origin as u32 }
+        particles.extend_from_slice(&galaxy);
+        centres.push(particles.len() as u32 - 1);
+    };
+    for (ix, p) in particles.iter_mut().enumerate() {
+        p.id = ix as u32;
+    }
+    let solver: Box<dyn ForceSolver3> = match cfg.sim {
+        config::SimType::BarnesHut => Box::new(octree::BarnesHut3::new(None)),
+        config::SimType::BarnesHutParallel => Box::new(octree::BarnesHut3::new(Some(pool))),
+        config::SimType::Classical => Box::new(physics3d::Classical3),
+        _ => unreachable!("config::validate lets no other solver through in 3D")
+    };
+    let pots = potentials(cfg, &centres);
+    if pots.len() == 0 {
+        (particles, solver)
+    } else {
+        (particles, Box::new(external::External3::new(solver, pots)))
+    }
+}
+
+//comoving integration for cosmological runs, None for plain Newtonian ones
+fn init_cosmology(cfg: &Config) -> Option<cosmology::Cosmology> {
+    if cfg.cosmology {
+        Some(cosmology::Cosmology::new(cfg.omega_m, cfg.omega_l, cfg.hubble, cfg.a_start))
+    } else {
+        None
+    }
+}
+
+fn step2d(particles: &mut Vec<Particle>, solver: &mut dyn ForceSolver, cosmo: &mut Option<cosmology::Cosmology>,
+          frcs: &mut Vec<PhysVec>) {
+    match *cosmo {
+        Some(ref mut c) => cosmology::stepsim(particles, solver, c, frcs),
+        None            => physics::stepsim(particles, solver, frcs)
+    }
+}
+
+fn step3d(particles: &mut Vec<Particle3>, solver: &mut dyn ForceSolver3, cosmo: &mut Option<cosmology::Cosmology>,
+          frcs: &mut Vec<Vec3>) {
+    match *cosmo {
+        Some(ref mut c) => cosmology::stepsim3(particles, solver, c, frcs),
+        None            => physics3d::stepsim(particles, solver, frcs)
+    }
+}
+
+fn report_cosmology(cosmo: &Option<cosmology::Cosmology>) {
+    match *cosmo {
+        Some(ref c) => println!("Scale factor: {}", c.a),
+        None        => ()
+    }
+}
+
+fn run2d(cfg: &Config, pool: Rc<ThreadPool>, snapshot: Option<String>) {
+    let (mut particles, mut solver) = init_particles(cfg, pool);
+    let mut cosmo = init_cosmology(cfg);
+    let mut stepct = 0;
+    let mut simtime = 0.;
+    let mut collisions = collide::Collisions::new();
+    let mut frcs = Vec::new();
+    let mut active = Vec::new();
+    let block = timestep::BlockParams { max_level: cfg.block_levels, eta: cfg.eta, eps: cfg.step_eps };
+    let mut hydro = if particles.iter().any(|p| p.kind == config::Species::Gas) {
+        Some(sph::Sph::new(cfg.eos, cfg.gamma, cfg.sound_speed, cfg.sph_neighbours, cfg.sph_h,
+                           cfg.visc_alpha, cfg.visc_beta))
+    } else {
+        None
+    };
+    let mut holes = if particles.iter().any(|p| p.kind == config::Species::BlackHole) {
+        Some(blackhole::BlackHoles::new(cfg.bh_friction, cfg.bh_friction_radius,
+                                        cfg.bh_accretion_radius, cfg.bh_capture_radius))
+    } else {
+        None
     };
-    match cfg.sim {
-        config::SimType::BarnesHut => return (particles, barneshut::stepsim),
-        config::SimType::BarnesHutParallel => return (particles, barneshut::stepsim_par),
-        config::SimType::Classical => return (particles, physics::stepsim),
+    let mut escapes = escape::Escapes::new(cfg.escape_radius, cfg.escape,
+                                           cfg.escape_log.as_ref().map(|s| &s[..]));
+    let mut mergers = collide::MergeLog::new(cfg.merge_log.as_ref().map(|s| &s[..]));
+    animate(|| {
+        if cfg.sort_every > 0 && stepct % cfg.sort_every == 0 {
+            morton::sort_particles(&mut particles);
+        }
+        match hydro {
+            Some(ref mut h) => h.kick(&mut particles, unsafe { physics::DT }),
+            None            => ()
+        }
+        match cfg.timestepping {
+            config::Timestepping::Fixed    => step2d(&mut particles, &mut *solver, &mut cosmo, &mut frcs),
+            config::Timestepping::Block    => {
+                timestep::stepsim_block(&mut particles, &mut *solver, &block, &mut frcs, &mut active)
+            },
+            config::Timestepping::Adaptive => {
+                let amax = particles.iter().fold(0., |a, p| f64::max(a, p.acc.modulus()));
+                unsafe { physics::DT = timestep::adaptive_dt(amax, cfg.eta, cfg.step_eps, cfg.dt_min, cfg.dt_max) };
+                step2d(&mut particles, &mut *solver, &mut cosmo, &mut frcs)
+            }
+        }
+        simtime += unsafe { physics::DT };
+        match holes {
+            Some(ref mut h) => for m in h.step(&mut particles, unsafe { physics::DT }) {
+                mergers.record(simtime, "black-hole", &m);
+            },
+            None => ()
+        }
+        if cfg.merge_radius > 0. {
+            for m in collisions.merge(&mut particles, cfg.merge_radius) {
+                mergers.record(simtime, "collision", &m);
+            }
+        }
+        if cfg.hard_spheres {
+            collisions.bounce(&mut particles, cfg.restitution);
+        }
+        escapes.check(&mut particles, simtime);
+        stepct += 1;
+        pcls2points(&particles, cfg.display)
+    }, cfg.display);
+    println!("Simulated time: {}", simtime);
+    if mergers.count > 0 {
+        println!("Mergers: {}", mergers.count);
+    }
+    report_cosmology(&cosmo);
+    particles.extend_from_slice(&escapes.frozen);
+    match snapshot {
+        Some(path) => snapshot::write_snapshot(&path, &particles, simtime),
+        None       => ()
     }
 }
 
-fn animate(mut particles: Vec<Particle>, stepfn: fn(&mut Vec<Particle>), display: Display ) {
-    let sdl_context = sdl2::init(sdl2::INIT_VIDEO).unwrap();
-    let mut renderer = get_renderer(&sdl_context, display);
-    let mut drawer = renderer.drawer();
+//time reversibility check: n steps forward, reverse every velocity, n more steps, then compare
+//with where each particle began. The integrator and solver are the ones of a normal run, so
+//this measures how far they are from reversible, not just rounding
+fn reverse(cfg: &Config, pool: Rc<ThreadPool>, n: u32, errors: Option<String>) {
+    let (mut particles, mut solver) = init_particles(cfg, pool);
+    if particles.len() == 0 { fail("there are no particles to check") }
+    let starttime = time::precise_time_s();
+    let errs = round_trip(&mut particles, &mut *solver, n);
+    let endtime = time::precise_time_s();
+    let mut sorted = errs.clone();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let rms = (errs.iter().fold(0., |s, e| s + e * e) / errs.len() as f64).sqrt();
+    println!("{} steps each way over {} particles in {:.2}s", n, particles.len(), endtime - starttime);
+    println!("Position error: rms {:e}, median {:e}, max {:e}", rms, sorted[sorted.len() / 2], sorted[sorted.len() - 1]);
+    match errors {
+        Some(path) => snapshot::write_errors(&path, &particles, &errs),
+        None       => ()
+    }
+}
+
+//n steps forward and n back, returning how far each particle ends from its start
+fn round_trip(particles: &mut Vec<Particle>, solver: &mut dyn ForceSolver, n: u32) -> Vec<f64> {
+    let start: Vec<PhysVec> = particles.iter().map(|p| p.pos).collect();
+    let mut frcs = Vec::new();
+    for _ in 0..n {
+        physics::stepsim(particles, solver, &mut frcs);
+    }
+    flip(particles);
+    for _ in 0..n {
+        physics::stepsim(particles, solver, &mut frcs);
+    }
+    flip(particles);
+    particles.iter().zip(start.iter()).map(|(p, &s)| p.pos.separation(s).modulus()).collect()
+}
+
+fn flip(particles: &mut Vec<Particle>) {
+    for p in particles.iter_mut() {
+        p.vel = PhysVec { x: -p.vel.x, y: -p.vel.y };
+    }
+}
+
+//value below which a fraction q of the sorted values lie, if there are any
+fn percentile(sorted: &Vec<f64>, q: f64) -> Option<f64> {
+    if sorted.len() == 0 { return None }
+    let ix = ((sorted.len() - 1) as f64 * q).round() as usize;
+    Some(sorted[ix])
+}
+
+//rms, median and 99th percentile of the relative errors of frcs against exact, leaving
+//out particles with no exact force to measure against; None if that is all of them
+fn error_stats(frcs: &Vec<PhysVec>, exact: &Vec<PhysVec>) -> Option<(f64, f64, f64)> {
+    let mut errs: Vec<f64> = frcs.iter().zip(exact.iter()).filter(|&(_, e)| e.modulus() > 0.).map(|(f, e)| {
+        let mut d = *f;
+        d.sub(e);
+        d.modulus() / e.modulus()
+    }).collect();
+    errs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let rms = (errs.iter().fold(0., |s, e| s + e * e) / errs.len() as f64).sqrt();
+    match (percentile(&errs, 0.5), percentile(&errs, 0.99)) {
+        (Some(median), Some(top)) => Some((rms, median, top)),
+        _ => None
+    }
+}
+
+//Barnes-Hut forces at each threshold against direct summation, for the configured initial
+//conditions and external potentials. The configured Barnes-Hut variant is used; thresholds
+//mean nothing to the other solvers, so for them the serial tree stands in
+fn accuracy(cfg: &Config, pool: Rc<ThreadPool>, thresholds: Vec<f64>) {
+    let (mut particles, centres) = make_particles(cfg);
+    let (name, tree): (&str, Box<dyn ForceSolver>) = match cfg.sim {
+        config::SimType::BarnesHutParallel => ("barnes-hut-parallel", Box::new(barneshut::BarnesHutParallel::new(pool))),
+        config::SimType::BarnesHut => ("barnes-hut", Box::new(barneshut::BarnesHut::new())),
+        _ => ("barnes-hut (serial, in place of the configured solver)", Box::new(barneshut::BarnesHut::new()))
+    };
+    let mut tree = with_potentials(cfg, &centres, tree);
+    let mut direct = with_potentials(cfg, &centres, Box::new(physics::Classical));
+    let mut exact = vec![PhysVec {x: 0., y: 0.}; particles.len()];
+    let t0 = time::precise_time_s();
+    direct.forces(&particles, &mut exact);
+    let direct_time = time::precise_time_s() - t0;
+    // the relative error criterion compares against the previous acceleration
+    for (p, f) in particles.iter_mut().zip(exact.iter()) {
+        p.acc = PhysVec { x: f.x / p.mass, y: f.y / p.mass };
+    }
+    println!("{} particles, {}, {:?} criterion", particles.len(), name, cfg.criterion);
+    println!("{:>10} {:>12} {:>12} {:>12} {:>10}", "threshold", "rms", "median", "99th", "time (s)");
+    println!("{:>10} {:>12} {:>12} {:>12} {:>10.4}", "direct", "-", "-", "-", direct_time);
+    let mut frcs = vec![PhysVec {x: 0., y: 0.}; particles.len()];
+    for &thresh in &thresholds {
+        unsafe { barneshut::THRESH = thresh };
+        let t0 = time::precise_time_s();
+        tree.forces(&particles, &mut frcs);
+        let elapsed = time::precise_time_s() - t0;
+        match error_stats(&frcs, &exact) {
+            Some((rms, median, top)) =>
+                println!("{:>10} {:>12.3e} {:>12.3e} {:>12.3e} {:>10.4}", thresh, rms, median, top, elapsed),
+            // every exact force is zero, so there is nothing to measure against
+            None => println!("{:>10} {:>12} {:>12} {:>12} {:>10.4}", thresh, "-", "-", "-", elapsed)
+        }
+    }
+    unsafe { barneshut::THRESH = cfg.threshold };
+}
+
+fn run3d(cfg: &Config, pool: Rc<ThreadPool>, snapshot: Option<String>) {
+    let (mut particles, mut solver) = init_particles3(cfg, pool);
+    let mut cosmo = init_cosmology(cfg);
+    let mut simtime = 0.;
+    let mut stepct = 0;
+    let mut frcs = Vec::new();
+    let mut escapes = escape::Escapes3::new(cfg.escape_radius, cfg.escape,
+                                            cfg.escape_log.as_ref().map(|s| &s[..]));
+    animate(|| {
+        if cfg.sort_every > 0 && stepct % cfg.sort_every == 0 {
+            morton::sort_particles3(&mut particles);
+        }
+        match cfg.timestepping {
+            config::Timestepping::Fixed    => (),
+            config::Timestepping::Adaptive => {
+                let amax = particles.iter().fold(0., |a, p| f64::max(a, p.acc.modulus()));
+                unsafe { physics::DT = timestep::adaptive_dt(amax, cfg.eta, cfg.step_eps, cfg.dt_min, cfg.dt_max) };
+            },
+            config::Timestepping::Block    => unreachable!("config::validate rejects block steps in 3D")
+        }
+        step3d(&mut particles, &mut *solver, &mut cosmo, &mut frcs);
+        simtime += unsafe { physics::DT };
+        escapes.check(&mut particles, simtime);
+        stepct += 1;
+        project(&particles, cfg.display)
+    }, cfg.display);
+    println!("Simulated time: {}", simtime);
+    report_cosmology(&cosmo);
+    particles.extend_from_slice(&escapes.frozen);
+    match snapshot {
+        Some(path) => snapshot::write_snapshot3(&path, &particles, simtime),
+        None       => ()
+    }
+}
+
+//step and draw until the window is closed; frame advances the simulation and returns the points
+//to draw, one list per species
+fn animate<F: FnMut() -> Vec<Vec<Point>>>(mut frame: F, display: Display) {
+    let sdl_context = sdl2::init().unwrap();
+    let mut canvas = get_canvas(&sdl_context, display);
     let mut framect = 0;
     let starttime = time::precise_time_s();
-    let mut event_pump = sdl_context.event_pump();
+    let mut event_pump = sdl_context.event_pump().unwrap();
     'outer: loop {
-        drawer.clear();
-        stepfn(&mut particles);
-        let points = pcls2points(&particles, display);
-        drawer.draw_points(&points);
-        drawer.present();
+        canvas.set_draw_color(sdl2::pixels::Color::RGB(0, 0, 0));
+        canvas.clear();
+        for (points, &(r, g, b)) in frame().iter().zip(COLOURS.iter()) {
+            canvas.set_draw_color(sdl2::pixels::Color::RGB(r, g, b));
+            canvas.draw_points(&points[..]).unwrap();
+        }
+        canvas.present();
         framect += 1;
         for event in event_pump.poll_iter() {
             match event {
                 sdl2::event::Event::Quit{..} => break 'outer,
-                sdl2::event::Event::KeyDown { keycode:key, .. } => {
-                    if key == sdl2::keycode::KeyCode::Escape {
-                        break 'outer
-                    }
-                }
+                sdl2::event::Event::KeyDown { keycode: Some(sdl2::keyboard::Keycode::Escape), .. } => break 'outer,
                 _ => {}
             }
         }
@@ -73,45 +437,179 @@ fn animate(mut particles: Vec<Particle>, stepfn: fn(&mut Vec<Particle>), display
     println!("Avg FPS: {}", framect as f64 / (endtime - starttime) as f64)
 }
 
-fn get_renderer<'a>(_sdl: &sdl2::Sdl, display: Display) -> sdl2::render::Renderer<'a> {
-    sdl2::render::Renderer::new_with_window(_sdl, display.width, display.height, sdl2::video::FULLSCREEN).unwrap()
+fn get_canvas(sdl: &sdl2::Sdl, display: Display) -> sdl2::render::Canvas<sdl2::video::Window> {
+    let video = sdl.video().unwrap();
+    let window = video.window("univ", display.width as u32, display.height as u32).fullscreen().build().unwrap();
+    window.into_canvas().build().unwrap()
 }
 
 // ******* Configuration ******* //
 
+static DEFAULT_CONFIG: &str = "config/default.toml";
+
+//the settings in path laid over the defaults
 fn configure(path: &str) -> Config {
-    let mut cfgstr = String::new();
-    File::open(&Path::new(path)).unwrap().read_to_string(&mut cfgstr);
-    let cfgtbl = toml::Parser::new(&cfgstr).parse().unwrap();
-    let cfg: ConfigOpt = toml::decode(toml::Value::Table(cfgtbl)).unwrap();
+    let default: Config = read_toml(DEFAULT_CONFIG);
+    let cfg: ConfigOpt = read_toml(path);
+    config::merge(default, cfg)
+}
 
-    let mut defaultstr = String::new();
-    File::open(&Path::new("config/default.toml")).unwrap().read_to_string(&mut defaultstr);
-    let default_tbl = toml::Parser::new(&defaultstr).parse().unwrap();
-    let default: Config = toml::decode(toml::Value::Table(default_tbl)).unwrap();
-    println!("{:?}", default);
-    default
+fn read_toml<T: rustc_serialize::Decodable>(path: &str) -> T {
+    let mut text = String::new();
+    match File::open(Path::new(path)).and_then(|mut f| f.read_to_string(&mut text)) {
+        Ok(_)  => (),
+        Err(e) => fail(&format!("{}: {}", path, e))
+    }
+    let mut parser = toml::Parser::new(&text);
+    let table = match parser.parse() {
+        Some(table) => table,
+        None => {
+            let e = &parser.errors[0];
+            let (line, col) = parser.to_linecol(e.lo);
+            fail(&format!("{}:{}:{}: {}", path, line + 1, col + 1, e.desc))
+        }
+    };
+    match rustc_serialize::Decodable::decode(&mut toml::Decoder::new(toml::Value::Table(table))) {
+        Ok(v)  => v,
+        Err(e) => fail(&format!("{}: {}", path, e))
+    }
 }
 
-fn opts() -> String {
+//report a mistake in the config or on the command line and stop
+fn fail(msg: &str) -> ! {
+    let _ = writeln!(&mut io::stderr(), "{}", msg);
+    std::process::exit(1)
+}
+
+//whether the configured solver shares its work out over the thread pool
+fn uses_pool(cfg: &Config) -> bool {
+    matches!(cfg.sim, config::SimType::BarnesHutParallel | config::SimType::ClassicalParallel | config::SimType::TreePm)
+}
+
+fn opts() -> getopts::Matches {
     let args: Vec<String> = std::env::args().map(|x| x.to_string()).collect(); 
     let mut opts = getopts::Options::new();
     opts.optopt("c", "config", "Configuration file", "PATH");
-    let matches = match opts.parse(args.tail()) {
+    opts.optopt("t", "threads", "Number of worker threads (overrides the config file)", "N");
+    opts.optopt("s", "snapshot", "Write the final state of the particles to this file", "PATH");
+    opts.optopt("", "thresholds", "Comma separated thresholds for the accuracy command \
+                                   (default: the configured threshold times 0.25, 0.5, 1, 2 and 4)", "LIST");
+    opts.optopt("r", "reverse", "Run N steps forward and N back without display, reporting how far each particle \
+                                 ends from its start", "N");
+    opts.optopt("", "errors", "Write each particle's error from the reversibility check to this file", "PATH");
+    match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(f) => panic!("{}", f)
-    };
-    match matches.opt_str("c") {
-        Some(c) => return c,
-        None    => return String::from_str("config/default.toml")
     }
 }
 
 fn main() {
-    let pathstr = opts();
+    let matches = opts();
+    let pathstr = match matches.opt_str("c") {
+        Some(c) => c,
+        None    => String::from(DEFAULT_CONFIG)
+    };
     let cfg = configure(&pathstr);
+    match config::validate(&cfg) {
+        Ok(())   => (),
+        Err(msg) => fail(&format!("{}: {}", pathstr, msg))
+    }
     unsafe {barneshut::THRESH = cfg.threshold};
+    unsafe {barneshut::CRITERION = cfg.criterion};
     unsafe {physics::DT = cfg.dt};
-    let (particles, stepfn) = init_particles(&cfg);
-    animate(particles, stepfn, cfg.display);
+    unsafe {physics::BOX_SIZE = cfg.box_size};
+    let nthreads = match matches.opt_str("t") {
+        Some(n) => match n.parse() {
+            Ok(n) if n > 0 => n,
+            _ => fail(&format!("--threads wants a positive number of threads, not '{}'", n))
+        },
+        None    => cfg.threads.unwrap_or(num_cpus::get())
+    };
+    // a pool of one starts no threads
+    let nthreads = if uses_pool(&cfg) { nthreads } else { 1 };
+    let pool = Rc::new(ThreadPool::new(nthreads));
+    if matches.free.len() > 0 && matches.free[0] == "accuracy" {
+        if cfg.dimensions == 3 { fail("the accuracy report is only available in 2D") }
+        let thresholds = match matches.opt_str("thresholds") {
+            Some(list) => list.split(',').map(|t| match t.trim().parse::<f64>() {
+                Ok(t) if t > 0. => t,
+                _ => fail(&format!("--thresholds wants a comma separated list of positive numbers, not '{}'", list))
+            }).collect(),
+            None       => [0.25, 0.5, 1., 2., 4.].iter().map(|f| f * cfg.threshold).collect()
+        };
+        return accuracy(&cfg, pool, thresholds)
+    }
+    match matches.opt_str("r") {
+        Some(n) => {
+            match config::validate_reverse(&cfg) {
+                Ok(())   => (),
+                Err(msg) => fail(&format!("{}: {}", pathstr, msg))
+            }
+            let n = match n.parse() {
+                Ok(n) => n,
+                Err(_) => fail(&format!("--reverse wants a number of steps, not '{}'", n))
+            };
+            return reverse(&cfg, pool, n, matches.opt_str("errors"))
+        },
+        None => ()
+    }
+    if cfg.dimensions == 3 {
+        run3d(&cfg, pool, matches.opt_str("s"));
+    } else {
+        run2d(&cfg, pool, matches.opt_str("s"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use barneshut::{BarnesHut, THRESH};
+    use physics::{Particle, PhysVec, Classical, DT};
+    use physics::tests::{lock, random_particles, forces_of, exact_forces};
+    use super::{round_trip, percentile, error_stats};
+
+    #[test]
+    fn round_trip_returns_to_start() {
+        let _g = lock();
+        // a skewed lattice, with nobody close enough to need tiny steps
+        let particles: Vec<Particle> = (0..30).map(|k| {
+            let (i, j) = ((k % 6) as f64, (k / 6) as f64);
+            Particle::new(PhysVec { x: 15. * i + 3. * j, y: 15. * j + 2. * (i * i % 5.) }, PhysVec { x: 0.1 * j, y: -0.1 * i }, 1.)
+        }).collect();
+        let max_error = |dt: f64, n: u32| {
+            unsafe { DT = dt };
+            let mut moved = particles.clone();
+            let errs = round_trip(&mut moved, &mut Classical, n);
+            errs.iter().fold(0., |m: f64, &e| m.max(e))
+        };
+        // the same span of time in ever smaller steps: each step back misses by a dt^2
+        let (coarse, fine) = (max_error(0.02, 25), max_error(0.005, 100));
+        assert!(coarse < 1e-2, "max error {}", coarse);
+        assert!(fine < coarse / 2., "max error {} with smaller steps, {} with larger", fine, coarse);
+    }
+
+    #[test]
+    fn reported_error_falls_with_threshold() {
+        let _g = lock();
+        let particles = random_particles(1000, 100.);
+        let exact = exact_forces(&particles);
+        let stats: Vec<(f64, f64, f64)> = [0.5, 1., 2., 4.].iter().map(|&t| {
+            unsafe { THRESH = t };
+            error_stats(&forces_of(&mut BarnesHut::new(), &particles), &exact).unwrap()
+        }).collect();
+        for w in stats.windows(2) {
+            assert!(w[1].0 < w[0].0 && w[1].1 < w[0].1, "rms and median {:?} then {:?}", w[0], w[1]);
+        }
+        for &(_, median, top) in &stats {
+            assert!(median <= top);
+        }
+    }
+
+    #[test]
+    fn nothing_to_measure() {
+        assert_eq!(percentile(&Vec::new(), 0.5), None);
+        assert_eq!(percentile(&vec![1., 2., 3.], 0.99), Some(3.));
+        let zero = vec![PhysVec { x: 0., y: 0. }; 3];
+        assert_eq!(error_stats(&zero, &zero), None);
+        assert_eq!(error_stats(&Vec::new(), &Vec::new()), None);
+    }
 }
@@ -0,0 +1,121 @@
+use complex::Complex;
+use config::{FieldCfg, Species};
+use fft;
+use physics::{Particle, PhysVec};
+use physics3d::gaussian;
+use std::f64;
+
+// Cosmological initial conditions. White noise on an n x n grid is shaped in Fourier space
+// to a power law spectrum P(k) ~ k^index, and the particles of a square lattice are moved
+// off their sites by the Zel'dovich displacement psi, where delta = -div psi, so that
+// psi_k = i k delta_k / k^2. The field is scaled to the requested rms density contrast,
+// and velocities are taken parallel to the displacements as in the growing mode.
+
+pub fn make_field(field: &FieldCfg) -> Vec<Particle> {
+    let n = field.grid as usize;
+    assert!(n.is_power_of_two(), "fields[].grid is checked by config::validate");
+    let spacing = field.size / n as f64;
+    let mut delta: Vec<Complex> = (0..n * n).map(|_| Complex::new(gaussian(), 0.)).collect();
+    fft::fft2(&mut delta, n, false);
+    let mut psix = vec![Complex::zero(); n * n];
+    let mut psiy = vec![Complex::zero(); n * n];
+    let kunit = 2. * f64::consts::PI / field.size;
+    let wave = |i: usize| if i < n / 2 { i as f64 } else { i as f64 - n as f64 };
+    for iy in 0..n {
+        for ix in 0..n {
+            let c = iy * n + ix;
+            // the mean and the unpaired nyquist modes are dropped to keep the field real
+            if (ix == 0 && iy == 0) || ix == n / 2 || iy == n / 2 {
+                delta[c] = Complex::zero();
+                continue
+            }
+            let (kx, ky) = (kunit * wave(ix), kunit * wave(iy));
+            let k2 = kx * kx + ky * ky;
+            delta[c] = delta[c].scale(k2.powf(field.spectral_index / 4.));
+            psix[c] = Complex::new(0., kx / k2) * delta[c];
+            psiy[c] = Complex::new(0., ky / k2) * delta[c];
+        }
+    }
+    fft::fft2(&mut delta, n, true);
+    fft::fft2(&mut psix, n, true);
+    fft::fft2(&mut psiy, n, true);
+    let rms = (delta.iter().fold(0., |s, d| s + d.re * d.re) / (n * n) as f64).sqrt();
+    let norm = if rms > 0. { field.sigma / rms } else { 0. };
+
+    let mass = field.mass.unwrap_or(1.);
+    let vfac = field.velocity.unwrap_or(0.);
+    let centre = PhysVec { x: field.posx.unwrap_or(0.), y: field.posy.unwrap_or(0.) };
+    let bulk = PhysVec { x: field.velx.unwrap_or(0.), y: field.vely.unwrap_or(0.) };
+    let mut particles = Vec::with_capacity(n * n);
+    for iy in 0..n {
+        for ix in 0..n {
+            let c = iy * n + ix;
+            let psi = PhysVec { x: psix[c].re * norm, y: psiy[c].re * norm };
+            let site = PhysVec { x: (ix as f64 + 0.5) * spacing - field.size / 2.,
+                                 y: (iy as f64 + 0.5) * spacing - field.size / 2. };
+            let pos = PhysVec { x: centre.x + site.x + psi.x, y: centre.y + site.y + psi.y };
+            let vel = PhysVec { x: bulk.x + vfac * psi.x, y: bulk.y + vfac * psi.y };
+            let mut p = Particle::new(pos, vel, mass);
+            p.kind = Species::DarkMatter;
+            particles.push(p);
+        }
+    }
+    particles
+}
+
+#[cfg(test)]
+mod tests {
+    use complex::Complex;
+    use config::FieldCfg;
+    use fft;
+    use std::f64;
+    use super::make_field;
+
+    fn field(grid: u32, spectral_index: f64, sigma: f64) -> FieldCfg {
+        FieldCfg { grid: grid, size: 64., spectral_index: spectral_index, sigma: sigma, velocity: None, mass: None,
+                   posx: None, posy: None, velx: None, vely: None }
+    }
+
+    //each particle's displacement from its lattice site
+    fn displacements(cfg: &FieldCfg) -> (Vec<Complex>, Vec<Complex>) {
+        let n = cfg.grid as usize;
+        let spacing = cfg.size / n as f64;
+        let particles = make_field(cfg);
+        let site = |i: usize| (i as f64 + 0.5) * spacing - cfg.size / 2.;
+        let psix = particles.iter().enumerate().map(|(c, p)| Complex::new(p.pos.x - site(c % n), 0.)).collect();
+        let psiy = particles.iter().enumerate().map(|(c, p)| Complex::new(p.pos.y - site(c / n), 0.)).collect();
+        (psix, psiy)
+    }
+
+    #[test]
+    fn divergence_matches_sigma() {
+        for &index in &[-1., 0., 1.] {
+            let cfg = field(32, index, 0.2);
+            let n = cfg.grid as usize;
+            let (mut psix, mut psiy) = displacements(&cfg);
+            // delta = -div psi, taken spectrally
+            fft::fft2(&mut psix, n, false);
+            fft::fft2(&mut psiy, n, false);
+            let kunit = 2. * f64::consts::PI / cfg.size;
+            let wave = |i: usize| kunit * if i < n / 2 { i as f64 } else { i as f64 - n as f64 };
+            let mut delta: Vec<Complex> = (0..n * n).map(|c| {
+                let (kx, ky) = (wave(c % n), wave(c / n));
+                (Complex::new(0., kx) * psix[c] + Complex::new(0., ky) * psiy[c]).scale(-1.)
+            }).collect();
+            fft::fft2(&mut delta, n, true);
+            let rms = (delta.iter().fold(0., |s, d| s + d.re * d.re) / (n * n) as f64).sqrt();
+            assert!((rms - cfg.sigma).abs() < 1e-9, "rms divergence {} for sigma {}", rms, cfg.sigma);
+            assert!(delta.iter().all(|d| d.im.abs() < 1e-9));
+        }
+    }
+
+    #[test]
+    fn displacement_has_zero_mean() {
+        let cfg = field(64, -1., 0.5);
+        let (psix, psiy) = displacements(&cfg);
+        let count = psix.len() as f64;
+        let (mx, my) = (psix.iter().fold(0., |s, d| s + d.re) / count, psiy.iter().fold(0., |s, d| s + d.re) / count);
+        let spacing = cfg.size / cfg.grid as f64;
+        assert!(mx.abs() < 1e-9 * spacing && my.abs() < 1e-9 * spacing, "mean displacement ({}, {})", mx, my);
+    }
+}